@@ -20,11 +20,119 @@
 #define LFX2_API
 #endif
 
+/**
+ * Controller that turns the queueing delay error into a correction.
+ */
+typedef enum lfx2ErrorController {
+  /**
+   * Corrects `alpha` times the error on every frame.
+   */
+  lfx2ErrorControllerProportional,
+  /**
+   * Adds integral and filtered derivative terms on top of the proportional correction.
+   */
+  lfx2ErrorControllerPid,
+} lfx2ErrorController;
+
+typedef enum lfx2EstimatorKind {
+  /**
+   * Exponentially weighted moving average with smoothing factor `beta`.
+   */
+  lfx2EstimatorKindEwma,
+  /**
+   * 1-D Kalman filter that adapts its gain to the observed jitter and load changes.
+   */
+  lfx2EstimatorKindKalman,
+  /**
+   * The `quantile` of the last `quantile_window` samples, regardless of the time between them.
+   */
+  lfx2EstimatorKindQuantile,
+} lfx2EstimatorKind;
+
+/**
+ * Trade-off between latency and throughput.
+ */
+typedef enum lfx2PacingMode {
+  /**
+   * Keeps the queueing delay at the margin.
+   */
+  lfx2PacingModeMinimumLatency,
+  /**
+   * Lets the queueing delay grow up to `latency_budget`, absorbing more jitter.
+   */
+  lfx2PacingModeBalanced,
+  /**
+   * Lets frames run freely, and only paces while the queueing delay exceeds `latency_budget`.
+   */
+  lfx2PacingModeThroughput,
+} lfx2PacingMode;
+
+/**
+ * Outcome of a fallible call. Details of a failure are available from
+ * `lfx2GetLastErrorMessage()`.
+ */
+typedef enum lfx2Result {
+  lfx2ResultSuccess,
+  /**
+   * Pacing is disabled for the context after an earlier error. Outputs are still valid, but
+   * frames are not paced.
+   */
+  lfx2ResultDegraded,
+  lfx2ResultInvalidArgument,
+  /**
+   * The device lacks a required feature.
+   */
+  lfx2ResultUnsupported,
+  /**
+   * The graphics API returned an error.
+   */
+  lfx2ResultDeviceError,
+  lfx2ResultInternalError,
+} lfx2Result;
+
 typedef enum lfx2MarkType {
   lfx2MarkTypeBegin,
   lfx2MarkTypeEnd,
 } lfx2MarkType;
 
+typedef enum lfx2ProfilerRecordKind {
+  lfx2ProfilerRecordKindMarkBegin,
+  lfx2ProfilerRecordKindMarkEnd,
+  /**
+   * `values` holds the end of the sleep.
+   */
+  lfx2ProfilerRecordKindSleep,
+  /**
+   * `values` holds the latency and the queueing delay.
+   */
+  lfx2ProfilerRecordKindLatency,
+  /**
+   * `values` holds the interval between the beginnings and between the ends of this frame
+   * and the previous one.
+   */
+  lfx2ProfilerRecordKindFrameTime,
+  /**
+   * `values` holds the margin.
+   */
+  lfx2ProfilerRecordKindMargin,
+  /**
+   * `values` holds the `lfx2PacingMode` and the latency budget.
+   */
+  lfx2ProfilerRecordKindMode,
+  /**
+   * `values` holds the rejected duration and the estimate it was compared to.
+   */
+  lfx2ProfilerRecordKindOutlier,
+  /**
+   * `values` holds the measured duration and the updated estimate.
+   */
+  lfx2ProfilerRecordKindEstimate,
+  /**
+   * `values` holds the number of events dropped so far.
+   */
+  lfx2ProfilerRecordKindDropped,
+} lfx2ProfilerRecordKind;
+
 typedef struct lfx2Context lfx2Context;
 
 #if (defined(LFX2_DX12) && defined(_WIN32))
@@ -54,8 +162,207 @@ typedef struct lfx2Dx12SubmitAux {
 typedef uint64_t lfx2Timestamp;
 typedef uint64_t lfx2Interval;
 
+/**
+ * Tuning parameters of the pacing controller.
+ */
+typedef struct lfx2ContextConfig {
+  enum lfx2PacingMode mode;
+  /**
+   * Queueing delay allowed by the balanced and throughput modes, in nanoseconds.
+   */
+  lfx2Interval latency_budget;
+  /**
+   * Maximum frame rate in frames per second. Zero disables the cap. Must be zero or in
+   * [0.1, 100000].
+   */
+  double frame_rate_limit;
+  /**
+   * Once the refresh period of a fixed-refresh display is set, frames are timed to complete
+   * this long before a vblank, in nanoseconds.
+   */
+  lfx2Interval vblank_slack;
+  /**
+   * Once the refresh range of a variable refresh rate display is set, frame intervals are kept
+   * this much longer than the minimum refresh period, in nanoseconds.
+   */
+  lfx2Interval vrr_margin;
+  /**
+   * A pause between frames longer than this multiple of the predicted frame time resets the
+   * pacing state. Zero disables gap detection. Must be in [0, 1024].
+   */
+  double gap_ratio;
+  /**
+   * Minimum pause between frames that resets the pacing state, in nanoseconds.
+   */
+  lfx2Interval min_gap;
+  /**
+   * Frames that are not released this long after their target are ignored, in nanoseconds.
+   * Zero disables the timeout.
+   */
+  lfx2Interval frame_timeout;
+  /**
+   * Marks later than this after their frame's target are rejected, in nanoseconds. Must be in
+   * (0, 10s].
+   */
+  lfx2Interval mark_horizon;
+  /**
+   * Gain of the queueing delay correction. Must be in (0, 1].
+   */
+  double alpha;
+  /**
+   * Smoothing factor of the per-section throughput estimators. Must be in (0, 1].
+   */
+  double beta;
+  /**
+   * Time constant of the per-section throughput estimators, in nanoseconds. When non-zero,
+   * samples are weighted by the time elapsed since the previous frame instead of by `beta`.
+   * The default matches a `beta` of 0.3 at 60 fps. The quantile estimator ignores it.
+   */
+  lfx2Interval estimator_time_constant;
+  /**
+   * Queueing delay the controller aims to keep, in nanoseconds. Only used until the jitter
+   * is known, or always if `margin_sigma` is zero.
+   */
+  lfx2Interval bias;
+  /**
+   * When non-zero, the queueing delay the controller aims to keep is this many standard
+   * deviations of the recent queueing delay and frame time jitter. Must be in [0, 16].
+   */
+  double margin_sigma;
+  /**
+   * Lower bound of the jitter-based margin, in nanoseconds.
+   */
+  lfx2Interval min_margin;
+  /**
+   * Bound of the queueing delay error fed into the correction, in nanoseconds. Used until the
+   * frame time is known, or always if `error_clamp_ratio` is zero.
+   */
+  lfx2Interval max_error;
+  /**
+   * Bound of the queueing delay error relative to the predicted frame time. Must be in [0, 16].
+   */
+  double error_clamp_ratio;
+  /**
+   * Upper bound of a section duration sample, in nanoseconds.
+   */
+  lfx2Interval max_frame_time;
+  /**
+   * Upper bound of a measured queueing delay, in nanoseconds.
+   */
+  lfx2Interval max_latency;
+  /**
+   * Raises `max_frame_time` and `max_latency` to this multiple of the predicted frame time for
+   * slow pipelines. Must be in [0, 64].
+   */
+  double frame_time_clamp_ratio;
+  /**
+   * Section durations exceeding the estimate by this many standard deviations are treated as
+   * one-off spikes. Zero disables spike detection. Must be in [0, 64].
+   */
+  double outlier_sigma;
+  /**
+   * Minimum excess over the estimate for a spike, in nanoseconds.
+   */
+  lfx2Interval outlier_min_deviation;
+  /**
+   * Weight of a spike's excess that is still fed into the estimator. Zero excludes spikes
+   * entirely. Must be in [0, 1].
+   */
+  double outlier_weight;
+  /**
+   * Number of consecutive spikes after which they are accepted as a real load change. Must be
+   * at least 1.
+   */
+  uint32_t outlier_persistence;
+  enum lfx2ErrorController error_controller;
+  /**
+   * Integral gain of the PID controller. Must be in [0, 1].
+   */
+  double pid_ki;
+  /**
+   * Derivative gain of the PID controller. Must be in [0, 1].
+   */
+  double pid_kd;
+  /**
+   * Smoothing factor of the derivative term of the PID controller. Must be in (0, 1].
+   */
+  double pid_derivative_filter;
+  /**
+   * Bound of the integral term of the PID controller, in nanoseconds.
+   */
+  lfx2Interval pid_integral_limit;
+  /**
+   * Quantile tracked by the quantile estimator. Must be in (0, 1].
+   */
+  double quantile;
+  /**
+   * Number of recent samples the quantile estimator considers. Must be in [1, 4096].
+   */
+  uint32_t quantile_window;
+  /**
+   * Validates handles passed to the C API, for the rest of the process. Only read when the
+   * context is created. Handles created earlier are not tracked and pass through unchecked. Can
+   * also be enabled by setting `LFX2_VALIDATE_HANDLES=1`.
+   */
+  bool validate_handles;
+  /**
+   * Writes traces for profiling. Only read when the context is created. Can also be enabled
+   * by setting `LFX2_PROFILE=1`.
+   */
+  bool profile;
+} lfx2ContextConfig;
+
+/**
+ * Counts marks that were inconsistent with the rest of their frame or with the previous frame.
+ */
+typedef struct lfx2MarkDiagnostics {
+  /**
+   * Marks later than `mark_horizon` after the frame's predicted begin. They are ignored.
+   */
+  uint64_t future;
+  /**
+   * Repeated marks of the same section and type. They are ignored.
+   */
+  uint64_t duplicate;
+  /**
+   * Begin and End marks of a section that were in the wrong order. The later mark is moved to
+   * the earlier one.
+   */
+  uint64_t inverted;
+  /**
+   * Begin marks earlier than the Begin of a preceding section. They are moved to it.
+   */
+  uint64_t non_monotonic;
+  /**
+   * Marks earlier than the same mark of the previous frame. They are moved to it.
+   */
+  uint64_t out_of_order;
+} lfx2MarkDiagnostics;
+
 typedef uint32_t lfx2SectionId;
 
+/**
+ * A profiling event in a fixed layout for the C API.
+ */
+typedef struct lfx2ProfilerRecord {
+  enum lfx2ProfilerRecordKind kind;
+  /**
+   * Zero if the event does not belong to a frame.
+   */
+  uint64_t frame_id;
+  /**
+   * Zero if the event does not belong to a section.
+   */
+  lfx2SectionId section_id;
+  lfx2Timestamp timestamp;
+  /**
+   * Interpreted according to `kind`. Unused values are zero.
+   */
+  uint64_t values[2];
+} lfx2ProfilerRecord;
+
+typedef void (*lfx2ProfilerCallback)(void *user_data, const struct lfx2ProfilerRecord *record);
+
 #if defined(LFX2_VK)
 typedef struct lfx2VulkanSubmitAux {
   VkCommandBuffer submit_before;
@@ -70,21 +377,44 @@ extern "C" {
 #endif // __cplusplus
 
 #if (defined(LFX2_DX12) && defined(_WIN32))
+/**
+ * Returns null on failure.
+ */
 LFX2_API struct lfx2Dx12Context *lfx2Dx12ContextCreate(ID3D12Device* device);
 
+LFX2_API
+enum lfx2Result lfx2Dx12ContextCreateChecked(ID3D12Device* device,
+                                             struct lfx2Dx12Context **out_context);
+
 LFX2_API void lfx2Dx12ContextAddRef(struct lfx2Dx12Context *context);
 
 LFX2_API void lfx2Dx12ContextRelease(struct lfx2Dx12Context *context);
 
+/**
+ * Objects are null if there is nothing to submit, or if pacing was disabled after an error.
+ */
 LFX2_API
 struct lfx2Dx12SubmitAux lfx2Dx12ContextBeforeSubmit(struct lfx2Dx12Context *context,
                                                      ID3D12CommandQueue* queue);
 
+/**
+ * Objects are null if there is nothing to submit, or if the result is not `lfx2ResultSuccess`.
+ */
+LFX2_API
+enum lfx2Result lfx2Dx12ContextBeforeSubmitChecked(struct lfx2Dx12Context *context,
+                                                   ID3D12CommandQueue* queue,
+                                                   struct lfx2Dx12SubmitAux *out_aux);
+
 LFX2_API void lfx2Dx12ContextBeginFrame(struct lfx2Dx12Context *context, struct lfx2Frame *frame);
 
 LFX2_API void lfx2Dx12ContextEndFrame(struct lfx2Dx12Context *context, struct lfx2Frame *frame);
 #endif
 
+/**
+ * Returns the message of the last failing call on the calling thread.
+ */
+LFX2_API const char *lfx2GetLastErrorMessage(void);
+
 LFX2_API lfx2Timestamp lfx2TimestampNow(void);
 
 #if defined(_WIN32)
@@ -95,6 +425,123 @@ LFX2_API void lfx2SleepUntil(lfx2Timestamp target);
 
 LFX2_API struct lfx2Context *lfx2ContextCreate(void);
 
+LFX2_API struct lfx2ContextConfig lfx2ContextConfigDefault(void);
+
+/**
+ * Returns null if the config is null or invalid.
+ */
+LFX2_API struct lfx2Context *lfx2ContextCreateWithConfig(const struct lfx2ContextConfig *config);
+
+/**
+ * Uses the default config if `config` is null.
+ */
+LFX2_API
+enum lfx2Result lfx2ContextCreateChecked(const struct lfx2ContextConfig *config,
+                                         struct lfx2Context **out_context);
+
+/**
+ * Returns false and keeps the current config if the new config is null or invalid.
+ */
+LFX2_API
+bool lfx2ContextSetConfig(struct lfx2Context *context,
+                          const struct lfx2ContextConfig *config);
+
+/**
+ * Returns false and keeps the current mode if the latency budget is out of range.
+ */
+LFX2_API
+bool lfx2ContextSetMode(struct lfx2Context *context,
+                        enum lfx2PacingMode mode,
+                        lfx2Interval latency_budget);
+
+/**
+ * Caps the frame rate in frames per second, or removes the cap if `frame_rate_limit` is zero.
+ * Returns false and keeps the current cap if the frame rate is out of range.
+ */
+LFX2_API
+bool lfx2ContextSetFrameRateLimit(struct lfx2Context *context, double frame_rate_limit);
+
+/**
+ * Aligns frames to the vblanks of a fixed-refresh display, or stops aligning them if
+ * `refresh_period` is zero. Vblanks must be reported with lfx2ContextReportVblank().
+ */
+LFX2_API
+void lfx2ContextSetRefreshPeriod(struct lfx2Context *context, lfx2Interval refresh_period);
+
+/**
+ * Reports the timestamp of a vblank. Not every vblank needs to be reported.
+ */
+LFX2_API void lfx2ContextReportVblank(struct lfx2Context *context, lfx2Timestamp timestamp);
+
+/**
+ * Keeps frames within the refresh range of a variable refresh rate display, or removes the
+ * constraint if both periods are zero. Returns false and keeps the current range if the periods
+ * are out of range, including a maximum period below 1ms.
+ */
+LFX2_API
+bool lfx2ContextSetVrrRange(struct lfx2Context *context,
+                            lfx2Interval min_period,
+                            lfx2Interval max_period);
+
+/**
+ * Stops pacing until lfx2ContextResume() is called, such as while a window is minimized. Frames
+ * created in the meantime start immediately and are not measured.
+ */
+LFX2_API void lfx2ContextPause(struct lfx2Context *context);
+
+LFX2_API void lfx2ContextResume(struct lfx2Context *context);
+
+/**
+ * Returns true if pacing was disabled after an internal error. Frames then start immediately
+ * and their marks are ignored.
+ */
+LFX2_API bool lfx2ContextIsDegraded(struct lfx2Context *context);
+
+/**
+ * Returns the number of frames that were not released within `frame_timeout` and were ignored.
+ */
+LFX2_API uint64_t lfx2ContextGetLeakedFrameCount(struct lfx2Context *context);
+
+/**
+ * Returns the number of marks that were repaired or ignored because they were inconsistent with
+ * the rest of their frame or with the previous frame.
+ */
+LFX2_API struct lfx2MarkDiagnostics lfx2ContextGetMarkDiagnostics(struct lfx2Context *context);
+
+/**
+ * Calls `callback` with every profiling event from now on, on the profiler thread. The callback
+ * must not call back into the context.
+ */
+LFX2_API
+void lfx2ContextAddProfilerCallback(struct lfx2Context *context,
+                                    lfx2ProfilerCallback callback,
+                                    void *user_data);
+
+/**
+ * Keeps the latest `capacity` profiling events in memory, to be read with
+ * lfx2ContextReadProfilerRing(). Calls after the first one are ignored. Returns false if
+ * `capacity` exceeds 1048576 events.
+ */
+LFX2_API bool lfx2ContextEnableProfilerRing(struct lfx2Context *context, size_t capacity);
+
+/**
+ * Moves up to `capacity` of the oldest events kept in memory to `out_records`, and returns how
+ * many were written.
+ */
+LFX2_API
+size_t lfx2ContextReadProfilerRing(struct lfx2Context *context,
+                                   struct lfx2ProfilerRecord *out_records,
+                                   size_t capacity);
+
+/**
+ * Replaces the throughput estimator of a section. The section's history is discarded when the
+ * kind changes.
+ */
+LFX2_API
+void lfx2ContextSetSectionEstimator(struct lfx2Context *context,
+                                    lfx2SectionId section_id,
+                                    enum lfx2EstimatorKind kind);
+
 LFX2_API void lfx2ContextAddRef(struct lfx2Context *context);
 
 LFX2_API void lfx2ContextRelease(struct lfx2Context *context);
@@ -103,16 +550,40 @@ LFX2_API
 struct lfx2Frame *lfx2FrameCreate(struct lfx2Context *context,
                                   lfx2Timestamp *out_timestamp);
 
+/**
+ * Returns `lfx2ResultDegraded` along with an unpaced frame if pacing is disabled.
+ */
+LFX2_API
+enum lfx2Result lfx2FrameCreateChecked(struct lfx2Context *context,
+                                       struct lfx2Frame **out_frame,
+                                       lfx2Timestamp *out_timestamp);
+
 LFX2_API void lfx2FrameAddRef(struct lfx2Frame *frame);
 
 LFX2_API void lfx2FrameRelease(struct lfx2Frame *frame);
 
+/**
+ * Marks a frame that will never be presented, such that it is not measured. The frame must
+ * still be released.
+ */
+LFX2_API void lfx2FrameCancel(struct lfx2Frame *frame);
+
 LFX2_API
 void lfx2MarkSection(struct lfx2Frame *frame,
                      lfx2SectionId section_id,
                      enum lfx2MarkType mark_type,
                      lfx2Timestamp timestamp);
 
+/**
+ * Like `lfx2MarkSection()`, but reports marks that were ignored because they are inconsistent
+ * with the rest of the frame, the frame is no longer tracked, or pacing is disabled.
+ */
+LFX2_API
+enum lfx2Result lfx2MarkSectionChecked(struct lfx2Frame *frame,
+                                       lfx2SectionId section_id,
+                                       enum lfx2MarkType mark_type,
+                                       lfx2Timestamp timestamp);
+
 LFX2_API
 void lfx2FrameOverrideQueuingDelay(struct lfx2Frame *frame,
                                    lfx2SectionId section_id,
@@ -138,6 +609,9 @@ struct lfx2Frame *lfx2FrameDequeueImplicit(struct lfx2ImplicitContext *context,
                                            bool critical);
 
 #if defined(LFX2_VK)
+/**
+ * Returns null on failure.
+ */
 LFX2_API
 struct lfx2VulkanContext *lfx2VulkanContextCreate(PFN_vkGetInstanceProcAddr gipa,
                                                   VkInstance instance,
@@ -145,13 +619,36 @@ struct lfx2VulkanContext *lfx2VulkanContextCreate(PFN_vkGetInstanceProcAddr gipa
                                                   VkDevice device,
                                                   uint32_t queue_family_index);
 
+/**
+ * Returns `lfx2ResultUnsupported` if Vulkan 1.3 or VK_EXT_calibrated_timestamps is unavailable.
+ */
+LFX2_API
+enum lfx2Result lfx2VulkanContextCreateChecked(PFN_vkGetInstanceProcAddr gipa,
+                                               VkInstance instance,
+                                               VkPhysicalDevice physical_device,
+                                               VkDevice device,
+                                               uint32_t queue_family_index,
+                                               struct lfx2VulkanContext **out_context);
+
 LFX2_API void lfx2VulkanContextAddRef(struct lfx2VulkanContext *context);
 
 LFX2_API void lfx2VulkanContextRelease(struct lfx2VulkanContext *context);
 
+/**
+ * Handles are null if pacing was disabled after an error, in which case they must not be
+ * submitted.
+ */
 LFX2_API
 struct lfx2VulkanSubmitAux lfx2VulkanContextBeforeSubmit(struct lfx2VulkanContext *context);
 
+/**
+ * Handles are null if the result is not `lfx2ResultSuccess`, in which case they must not be
+ * submitted.
+ */
+LFX2_API
+enum lfx2Result lfx2VulkanContextBeforeSubmitChecked(struct lfx2VulkanContext *context,
+                                                     struct lfx2VulkanSubmitAux *out_aux);
+
 LFX2_API
 void lfx2VulkanContextBeginFrame(struct lfx2VulkanContext *context,
                                  struct lfx2Frame *frame);
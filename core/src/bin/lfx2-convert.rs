@@ -0,0 +1,58 @@
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use latencyflex2_rust::{BinaryReader, JsonSink, ProfilerSink};
+
+/// Converts a binary trace written with `LFX2_PROFILE_FORMAT=binary` to a Chrome trace.
+fn main() -> ExitCode {
+    let args: Vec<_> = env::args_os().skip(1).collect();
+    let (input, output) = match &args[..] {
+        [input] => (
+            PathBuf::from(input),
+            PathBuf::from(input).with_extension("json"),
+        ),
+        [input, output] => (PathBuf::from(input), PathBuf::from(output)),
+        _ => {
+            eprintln!("Usage: lfx2-convert <trace.bin> [<trace.json>]");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let reader = File::open(&input).and_then(|file| BinaryReader::new(BufReader::new(file)));
+    let mut reader = match reader {
+        Ok(reader) => reader,
+        Err(e) => {
+            eprintln!("Failed to read {}: {e}", input.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut sink = match File::create(&output) {
+        Ok(file) => JsonSink::new(file),
+        Err(e) => {
+            eprintln!("Failed to create {}: {e}", output.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut events = 0;
+    for event in reader.by_ref() {
+        sink.record(&event);
+        events += 1;
+    }
+    drop(sink);
+
+    eprintln!("Wrote {events} events to {}", output.display());
+    if reader.skipped() > 0 {
+        eprintln!(
+            "Skipped {} events written by a newer version.",
+            reader.skipped()
+        );
+    }
+    if reader.incomplete() {
+        eprintln!("The trace ends with an incomplete event, which was ignored.");
+    }
+    ExitCode::SUCCESS
+}
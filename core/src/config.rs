@@ -0,0 +1,324 @@
+use std::fmt;
+
+use crate::Interval;
+
+/// Upper bound for any interval in the config. Keeps the controller arithmetic, which mixes
+/// signed and unsigned nanoseconds, far away from overflow.
+pub const MAX_INTERVAL: Interval = 10_000_000_000;
+
+/// Controller that turns the queueing delay error into a correction.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorController {
+    /// Corrects `alpha` times the error on every frame.
+    Proportional,
+    /// Adds integral and filtered derivative terms on top of the proportional correction.
+    Pid,
+}
+
+/// Trade-off between latency and throughput.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PacingMode {
+    /// Keeps the queueing delay at the margin.
+    MinimumLatency,
+    /// Lets the queueing delay grow up to `latency_budget`, absorbing more jitter.
+    Balanced,
+    /// Lets frames run freely, and only paces while the queueing delay exceeds `latency_budget`.
+    Throughput,
+}
+
+/// Tuning parameters of the pacing controller.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ContextConfig {
+    pub mode: PacingMode,
+    /// Queueing delay allowed by the balanced and throughput modes, in nanoseconds.
+    pub latency_budget: Interval,
+    /// Maximum frame rate in frames per second. Zero disables the cap. Must be zero or in
+    /// [0.1, 100000].
+    pub frame_rate_limit: f64,
+    /// Once the refresh period of a fixed-refresh display is set, frames are timed to complete
+    /// this long before a vblank, in nanoseconds.
+    pub vblank_slack: Interval,
+    /// Once the refresh range of a variable refresh rate display is set, frame intervals are kept
+    /// this much longer than the minimum refresh period, in nanoseconds.
+    pub vrr_margin: Interval,
+    /// A pause between frames longer than this multiple of the predicted frame time resets the
+    /// pacing state. Zero disables gap detection. Must be in [0, 1024].
+    pub gap_ratio: f64,
+    /// Minimum pause between frames that resets the pacing state, in nanoseconds.
+    pub min_gap: Interval,
+    /// Frames that are not released this long after their target are ignored, in nanoseconds.
+    /// Zero disables the timeout.
+    pub frame_timeout: Interval,
+    /// Marks later than this after their frame's target are rejected, in nanoseconds. Must be in
+    /// (0, 10s].
+    pub mark_horizon: Interval,
+    /// Gain of the queueing delay correction. Must be in (0, 1].
+    pub alpha: f64,
+    /// Smoothing factor of the per-section throughput estimators. Must be in (0, 1].
+    pub beta: f64,
+    /// Time constant of the per-section throughput estimators, in nanoseconds. When non-zero,
+    /// samples are weighted by the time elapsed since the previous frame instead of by `beta`.
+    /// The default matches a `beta` of 0.3 at 60 fps. The quantile estimator ignores it.
+    pub estimator_time_constant: Interval,
+    /// Queueing delay the controller aims to keep, in nanoseconds. Only used until the jitter
+    /// is known, or always if `margin_sigma` is zero.
+    pub bias: Interval,
+    /// When non-zero, the queueing delay the controller aims to keep is this many standard
+    /// deviations of the recent queueing delay and frame time jitter. Must be in [0, 16].
+    pub margin_sigma: f64,
+    /// Lower bound of the jitter-based margin, in nanoseconds.
+    pub min_margin: Interval,
+    /// Bound of the queueing delay error fed into the correction, in nanoseconds. Used until the
+    /// frame time is known, or always if `error_clamp_ratio` is zero.
+    pub max_error: Interval,
+    /// Bound of the queueing delay error relative to the predicted frame time. Must be in [0, 16].
+    pub error_clamp_ratio: f64,
+    /// Upper bound of a section duration sample, in nanoseconds.
+    pub max_frame_time: Interval,
+    /// Upper bound of a measured queueing delay, in nanoseconds.
+    pub max_latency: Interval,
+    /// Raises `max_frame_time` and `max_latency` to this multiple of the predicted frame time for
+    /// slow pipelines. Must be in [0, 64].
+    pub frame_time_clamp_ratio: f64,
+    /// Section durations exceeding the estimate by this many standard deviations are treated as
+    /// one-off spikes. Zero disables spike detection. Must be in [0, 64].
+    pub outlier_sigma: f64,
+    /// Minimum excess over the estimate for a spike, in nanoseconds.
+    pub outlier_min_deviation: Interval,
+    /// Weight of a spike's excess that is still fed into the estimator. Zero excludes spikes
+    /// entirely. Must be in [0, 1].
+    pub outlier_weight: f64,
+    /// Number of consecutive spikes after which they are accepted as a real load change. Must be
+    /// at least 1.
+    pub outlier_persistence: u32,
+    pub error_controller: ErrorController,
+    /// Integral gain of the PID controller. Must be in [0, 1].
+    pub pid_ki: f64,
+    /// Derivative gain of the PID controller. Must be in [0, 1].
+    pub pid_kd: f64,
+    /// Smoothing factor of the derivative term of the PID controller. Must be in (0, 1].
+    pub pid_derivative_filter: f64,
+    /// Bound of the integral term of the PID controller, in nanoseconds.
+    pub pid_integral_limit: Interval,
+    /// Quantile tracked by the quantile estimator. Must be in (0, 1].
+    pub quantile: f64,
+    /// Number of recent samples the quantile estimator considers. Must be in [1, 4096].
+    pub quantile_window: u32,
+    /// Validates handles passed to the C API, for the rest of the process. Only read when the
+    /// context is created. Handles created earlier are not tracked and pass through unchecked. Can
+    /// also be enabled by setting `LFX2_VALIDATE_HANDLES=1`.
+    pub validate_handles: bool,
+    /// Writes traces for profiling. Only read when the context is created. Can also be enabled
+    /// by setting `LFX2_PROFILE=1`.
+    pub profile: bool,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            mode: PacingMode::MinimumLatency,
+            latency_budget: 0,
+            frame_rate_limit: 0.,
+            vblank_slack: 1_000_000,
+            vrr_margin: 300_000,
+            gap_ratio: 8.,
+            min_gap: 100_000_000,
+            frame_timeout: 2_000_000_000,
+            mark_horizon: 2_000_000_000,
+            alpha: 0.15,
+            beta: 0.3,
+            estimator_time_constant: 46_700_000,
+            bias: 2_000_000,
+            margin_sigma: 3.,
+            min_margin: 500_000,
+            max_error: 25_000_000,
+            error_clamp_ratio: 1.5,
+            max_frame_time: 50_000_000,
+            max_latency: 200_000_000,
+            frame_time_clamp_ratio: 4.,
+            outlier_sigma: 4.,
+            outlier_min_deviation: 2_000_000,
+            outlier_weight: 0.,
+            outlier_persistence: 3,
+            error_controller: ErrorController::Proportional,
+            pid_ki: 0.02,
+            pid_kd: 0.05,
+            pid_derivative_filter: 0.3,
+            pid_integral_limit: 5_000_000,
+            quantile: 0.9,
+            quantile_window: 64,
+            validate_handles: false,
+            profile: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    field: &'static str,
+    expected: &'static str,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is out of range (expected {})",
+            self.field, self.expected
+        )
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub fn check(ok: bool, field: &'static str, expected: &'static str) -> Result<(), ConfigError> {
+    if ok {
+        Ok(())
+    } else {
+        Err(ConfigError { field, expected })
+    }
+}
+
+impl ContextConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let interval = |v: Interval| v > 0 && v <= MAX_INTERVAL;
+
+        check(
+            self.mode == PacingMode::MinimumLatency || interval(self.latency_budget),
+            "latency_budget",
+            "0 < latency_budget <= 10s unless in minimum latency mode",
+        )?;
+        check(
+            self.frame_rate_limit == 0. || (0.1..=100_000.).contains(&self.frame_rate_limit),
+            "frame_rate_limit",
+            "frame_rate_limit == 0 or 0.1 <= frame_rate_limit <= 100000",
+        )?;
+        check(
+            self.vblank_slack <= MAX_INTERVAL,
+            "vblank_slack",
+            "vblank_slack <= 10s",
+        )?;
+        check(
+            self.vrr_margin <= MAX_INTERVAL,
+            "vrr_margin",
+            "vrr_margin <= 10s",
+        )?;
+        check(
+            (0. ..=1024.).contains(&self.gap_ratio),
+            "gap_ratio",
+            "0 <= gap_ratio <= 1024",
+        )?;
+        check(interval(self.min_gap), "min_gap", "0 < min_gap <= 10s")?;
+        check(
+            self.frame_timeout <= MAX_INTERVAL,
+            "frame_timeout",
+            "frame_timeout <= 10s",
+        )?;
+        check(
+            interval(self.mark_horizon),
+            "mark_horizon",
+            "0 < mark_horizon <= 10s",
+        )?;
+        check(
+            self.alpha > 0. && self.alpha <= 1.,
+            "alpha",
+            "0 < alpha <= 1",
+        )?;
+        check(self.beta > 0. && self.beta <= 1., "beta", "0 < beta <= 1")?;
+        check(
+            self.estimator_time_constant <= MAX_INTERVAL,
+            "estimator_time_constant",
+            "estimator_time_constant <= 10s",
+        )?;
+        check(
+            interval(self.max_error),
+            "max_error",
+            "0 < max_error <= 10s",
+        )?;
+        check(
+            (0. ..=16.).contains(&self.error_clamp_ratio),
+            "error_clamp_ratio",
+            "0 <= error_clamp_ratio <= 16",
+        )?;
+        check(
+            interval(self.max_frame_time),
+            "max_frame_time",
+            "0 < max_frame_time <= 10s",
+        )?;
+        check(
+            interval(self.max_latency),
+            "max_latency",
+            "0 < max_latency <= 10s",
+        )?;
+        check(
+            (0. ..=64.).contains(&self.frame_time_clamp_ratio),
+            "frame_time_clamp_ratio",
+            "0 <= frame_time_clamp_ratio <= 64",
+        )?;
+        check(self.bias <= self.max_latency, "bias", "bias <= max_latency")?;
+        check(
+            (0. ..=16.).contains(&self.margin_sigma),
+            "margin_sigma",
+            "0 <= margin_sigma <= 16",
+        )?;
+        check(
+            self.min_margin <= self.max_error,
+            "min_margin",
+            "min_margin <= max_error",
+        )?;
+        check(
+            (0. ..=64.).contains(&self.outlier_sigma),
+            "outlier_sigma",
+            "0 <= outlier_sigma <= 64",
+        )?;
+        check(
+            self.outlier_min_deviation <= MAX_INTERVAL,
+            "outlier_min_deviation",
+            "outlier_min_deviation <= 10s",
+        )?;
+        check(
+            (0. ..=1.).contains(&self.outlier_weight),
+            "outlier_weight",
+            "0 <= outlier_weight <= 1",
+        )?;
+        check(
+            self.outlier_persistence >= 1,
+            "outlier_persistence",
+            "outlier_persistence >= 1",
+        )?;
+        check(
+            (0. ..=1.).contains(&self.pid_ki),
+            "pid_ki",
+            "0 <= pid_ki <= 1",
+        )?;
+        check(
+            (0. ..=1.).contains(&self.pid_kd),
+            "pid_kd",
+            "0 <= pid_kd <= 1",
+        )?;
+        check(
+            self.pid_derivative_filter > 0. && self.pid_derivative_filter <= 1.,
+            "pid_derivative_filter",
+            "0 < pid_derivative_filter <= 1",
+        )?;
+        check(
+            self.pid_integral_limit <= self.max_error,
+            "pid_integral_limit",
+            "pid_integral_limit <= max_error",
+        )?;
+        check(
+            self.quantile > 0. && self.quantile <= 1.,
+            "quantile",
+            "0 < quantile <= 1",
+        )?;
+        check(
+            (1..=4096).contains(&self.quantile_window),
+            "quantile_window",
+            "1 <= quantile_window <= 4096",
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use crate::config::ContextConfig;
+use crate::Interval;
+
+/// PID controller for the queueing delay error.
+///
+/// The proportional gain is `alpha`, so with `pid_ki` and `pid_kd` set to zero this reduces to
+/// the proportional correction.
+#[derive(Default)]
+pub struct PidController {
+    integral: f64,
+    last_error: Option<f64>,
+    derivative: f64,
+}
+
+impl PidController {
+    /// Returns the correction to apply to the queueing delay, which has the same sign as `error`
+    /// and is bounded by `limit`.
+    pub fn update(&mut self, config: &ContextConfig, error: f64, limit: Interval) -> f64 {
+        let limit = limit as f64;
+
+        if let Some(last_error) = self.last_error {
+            // First order low-pass, so that jitter in the measured delay does not get amplified.
+            self.derivative +=
+                config.pid_derivative_filter * (error - last_error - self.derivative);
+        }
+        self.last_error = Some(error);
+
+        let integral = self.integral + error;
+        let unclamped =
+            config.alpha * error + config.pid_ki * integral + config.pid_kd * self.derivative;
+        let output = unclamped.clamp(-limit, limit);
+
+        // Anti-windup: stop integrating while the output is saturated in the direction of the
+        // error, and bound the integral term so that it unwinds quickly once the load changes.
+        if output == unclamped || error.signum() != unclamped.signum() {
+            self.integral = integral;
+        }
+        if config.pid_ki > 0. {
+            let integral_limit = config.pid_integral_limit as f64 / config.pid_ki;
+            self.integral = self.integral.clamp(-integral_limit, integral_limit);
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ErrorController;
+
+    fn pid_config() -> ContextConfig {
+        ContextConfig {
+            error_controller: ErrorController::Pid,
+            ..Default::default()
+        }
+    }
+
+    /// Runs a queue whose delay grows by `drift` every frame on top of the applied correction,
+    /// like a bottleneck that is consistently slower than predicted. Returns the final error.
+    fn simulate(
+        config: &ContextConfig,
+        drift: f64,
+        steps: usize,
+        mut correct: impl FnMut(f64) -> f64,
+    ) -> f64 {
+        let bias = config.bias as f64;
+        let mut delay = 10_000_000.;
+        for _ in 0..steps {
+            let error = delay - bias;
+            delay = (delay - correct(error) + drift).max(0.);
+        }
+        delay - bias
+    }
+
+    #[test]
+    fn test_pid_removes_steady_state_offset() {
+        let config = pid_config();
+        let drift = 500_000.;
+
+        let proportional = simulate(&config, drift, 500, |e| config.alpha * e);
+        let mut pid = PidController::default();
+        let error = simulate(&config, drift, 500, |e| {
+            pid.update(&config, e, config.max_error)
+        });
+
+        assert!(proportional > 1_000_000., "{proportional}");
+        assert!(error.abs() < 10_000., "{error}");
+    }
+
+    #[test]
+    fn test_pid_recovers_from_saturation() {
+        let config = pid_config();
+        let limit = config.max_error as f64;
+        let mut pid = PidController::default();
+
+        // Hold the error at the clamp for a long time.
+        for _ in 0..1000 {
+            assert!(pid.update(&config, limit, config.max_error) <= limit);
+        }
+
+        // Once the disturbance is gone, the integral must not keep the queue drained.
+        let error = simulate(&config, 0., 200, |e| {
+            pid.update(&config, e, config.max_error)
+        });
+        assert!(error.abs() < 10_000., "{error}");
+    }
+
+    #[test]
+    fn test_pid_filters_jitter() {
+        let config = pid_config();
+        let mut pid = PidController::default();
+
+        let mut max_output: f64 = 0.;
+        for i in 0..1000 {
+            let jitter = if i % 2 == 0 { 1_000_000. } else { -1_000_000. };
+            max_output = max_output.max(pid.update(&config, jitter, config.max_error).abs());
+        }
+        // An unfiltered derivative term alone would contribute kd * 2ms every frame.
+        assert!(max_output < config.alpha * 1_000_000. + config.pid_kd * 1_000_000.);
+    }
+}
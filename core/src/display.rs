@@ -0,0 +1,147 @@
+use std::cmp;
+
+use crate::config::ContextConfig;
+use crate::estimator::Estimator;
+use crate::ewma::EwmaEstimator;
+use crate::{Interval, Timestamp};
+
+/// Gain of the phase correction of the vblank tracking filter.
+const PHASE_GAIN: f64 = 0.1;
+/// Gain of the period correction of the vblank tracking filter.
+const PERIOD_GAIN: f64 = 0.005;
+/// How far the tracked period may drift from the nominal one.
+const MAX_PERIOD_DRIFT: f64 = 0.05;
+/// Residuals larger than this fraction of the period are treated as a phase jump, such as after
+/// a mode switch, rather than as jitter.
+const RESYNC_THRESHOLD: f64 = 0.25;
+
+/// Alpha-beta filter over the vblank timestamps of a fixed-refresh display.
+///
+/// The reported refresh period is only a starting point: the period is refined from the reported
+/// vblanks, so that a clock that drifts against the CPU clock stays in phase.
+pub struct VblankTracker {
+    nominal_period: f64,
+    period: f64,
+    last_vblank: Option<Timestamp>,
+}
+
+impl VblankTracker {
+    pub fn new(refresh_period: Interval) -> VblankTracker {
+        VblankTracker {
+            nominal_period: refresh_period as f64,
+            period: refresh_period as f64,
+            last_vblank: None,
+        }
+    }
+
+    /// Reports a vblank. Vblanks that were not reported in between are accounted for.
+    pub fn vblank(&mut self, timestamp: Timestamp) {
+        let Some(last_vblank) = self.last_vblank.filter(|&last| timestamp > last) else {
+            self.last_vblank = Some(timestamp);
+            return;
+        };
+        let elapsed = (timestamp - last_vblank) as f64;
+        let periods = (elapsed / self.period).round().max(1.);
+        let residual = elapsed - periods * self.period;
+        if residual.abs() > RESYNC_THRESHOLD * self.period {
+            self.last_vblank = Some(timestamp);
+            return;
+        }
+
+        let predicted = last_vblank as f64 + periods * self.period;
+        self.last_vblank = Some((predicted + PHASE_GAIN * residual) as Timestamp);
+        self.period = (self.period + PERIOD_GAIN * residual / periods).clamp(
+            self.nominal_period * (1. - MAX_PERIOD_DRIFT),
+            self.nominal_period * (1. + MAX_PERIOD_DRIFT),
+        );
+    }
+
+    /// First vblank predicted at or after `timestamp`.
+    pub fn next_vblank(&self, timestamp: Timestamp) -> Option<Timestamp> {
+        let last_vblank = self.last_vblank?;
+        let Some(elapsed) = timestamp.checked_sub(last_vblank) else {
+            return Some(last_vblank);
+        };
+        let periods = (elapsed as f64 / self.period).ceil();
+        Some(last_vblank + (periods * self.period) as Interval)
+    }
+}
+
+/// Phase-locks frame starts to a fixed-refresh display, so that frames complete just before a
+/// vblank instead of waiting for most of a refresh period to be scanned out.
+pub struct DisplayPhase {
+    tracker: VblankTracker,
+    /// Time from the first to the last mark of a frame.
+    latency: EwmaEstimator,
+}
+
+impl DisplayPhase {
+    pub fn new(config: &ContextConfig, refresh_period: Interval) -> DisplayPhase {
+        DisplayPhase {
+            tracker: VblankTracker::new(refresh_period),
+            latency: EwmaEstimator::with_time_constant(config.beta, config.estimator_time_constant),
+        }
+    }
+
+    pub fn configure(&mut self, config: &ContextConfig) {
+        self.latency.configure(config);
+    }
+
+    pub fn vblank(&mut self, timestamp: Timestamp) {
+        self.tracker.vblank(timestamp);
+    }
+
+    pub fn observe_latency(&mut self, latency: Interval, elapsed: Option<Interval>) {
+        self.latency.update(latency as f64, elapsed);
+    }
+
+    /// Delays `target` by less than a refresh period, such that the frame is expected to
+    /// complete `vblank_slack` before a vblank.
+    pub fn align(&self, config: &ContextConfig, target: Timestamp) -> Timestamp {
+        let latency = self.latency.get() as Interval;
+        let completion = target.saturating_add(latency);
+        match self
+            .tracker
+            .next_vblank(completion.saturating_add(config.vblank_slack))
+        {
+            Some(vblank) => cmp::max(target, vblank.saturating_sub(config.vblank_slack + latency)),
+            None => target,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracks_drifting_period() {
+        let nominal: Interval = 16_666_667;
+        let actual = 16_700_000.;
+        let mut tracker = VblankTracker::new(nominal);
+        let start: Timestamp = 1_000_000_000_000;
+        let vblank_at = |i: u64| start + (i as f64 * actual) as Timestamp;
+
+        let mut x: u64 = 12345;
+        for i in 0..2000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            // 200us of reporting jitter, and every tenth vblank goes unreported.
+            let jitter = (x >> 56) as Timestamp * 200_000 / 256;
+            if i % 10 != 9 {
+                tracker.vblank(vblank_at(i) + jitter);
+            }
+        }
+
+        assert!(
+            (tracker.period - actual).abs() < 1_000.,
+            "{}",
+            tracker.period
+        );
+        let predicted = tracker.next_vblank(vblank_at(2000) - 1_000_000).unwrap();
+        let error = predicted as f64 - vblank_at(2000) as f64;
+        // The jitter is one-sided, so the phase settles at its mean.
+        assert!((error - 100_000.).abs() < 50_000., "{error}");
+    }
+}
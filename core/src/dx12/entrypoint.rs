@@ -1,7 +1,10 @@
 use crate::dx12::{Dx12Context, Dx12SubmitAux};
+use crate::error::{report, Error, ResultCode};
+use crate::handle::{self, HandleKind};
 use crate::time::timestamp_now;
 use crate::{Frame, MarkType};
 use std::mem::ManuallyDrop;
+use std::ptr;
 use std::sync::Arc;
 use windows::Win32::Graphics::Direct3D12::{ID3D12CommandQueue, ID3D12Device};
 
@@ -9,17 +12,53 @@ use windows::Win32::Graphics::Direct3D12::{ID3D12CommandQueue, ID3D12Device};
 pub unsafe extern "C" fn lfx2Dx12ContextCreate(
     device: ManuallyDrop<ID3D12Device>,
 ) -> *mut Dx12Context {
-    let context = Dx12Context::new(&device);
-    Arc::into_raw(context) as _
+    match Dx12Context::new(&device) {
+        Ok(context) => {
+            let context = Arc::into_raw(context);
+            handle::register(context, HandleKind::Dx12Context);
+            context as _
+        }
+        Err(e) => {
+            eprintln!("LFX2 WARN: Failed to create D3D12 context: {e}");
+            ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lfx2Dx12ContextCreateChecked(
+    device: ManuallyDrop<ID3D12Device>,
+    out_context: *mut *mut Dx12Context,
+) -> ResultCode {
+    if out_context.is_null() {
+        return report(Err(Error::InvalidArgument("out_context")));
+    }
+    *out_context = ptr::null_mut();
+    report(Dx12Context::new(&device).map(|context| {
+        *out_context = Arc::into_raw(context) as _;
+        handle::register(*out_context, HandleKind::Dx12Context);
+    }))
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2Dx12ContextAddRef(context: *mut Dx12Context) {
+    if !handle::guard(
+        "lfx2Dx12ContextAddRef",
+        handle::add_ref(context, HandleKind::Dx12Context),
+    ) {
+        return;
+    }
     Arc::increment_strong_count(context);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2Dx12ContextRelease(context: *mut Dx12Context) {
+    if !handle::guard(
+        "lfx2Dx12ContextRelease",
+        handle::release(context, HandleKind::Dx12Context),
+    ) {
+        return;
+    }
     Arc::decrement_strong_count(context);
 }
 
@@ -28,11 +67,43 @@ pub unsafe extern "C" fn lfx2Dx12ContextBeforeSubmit(
     context: *mut Dx12Context,
     queue: ManuallyDrop<ID3D12CommandQueue>,
 ) -> Dx12SubmitAux {
-    (*context).inner.lock().submit(&queue)
+    if !handle::valid(
+        "lfx2Dx12ContextBeforeSubmit",
+        context,
+        HandleKind::Dx12Context,
+    ) {
+        return Dx12SubmitAux::default();
+    }
+    (*context)
+        .inner
+        .lock()
+        .submit_or_fail(&queue)
+        .unwrap_or_default()
+}
+
+/// Objects are null if there is nothing to submit, or if the result is not `Success`.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2Dx12ContextBeforeSubmitChecked(
+    context: *mut Dx12Context,
+    queue: ManuallyDrop<ID3D12CommandQueue>,
+    out_aux: *mut Dx12SubmitAux,
+) -> ResultCode {
+    if out_aux.is_null() {
+        return report(Err(Error::InvalidArgument("out_aux")));
+    }
+    out_aux.write(Dx12SubmitAux::default());
+    if let Err(e) = handle::check(context, HandleKind::Dx12Context) {
+        return report(Err(e));
+    }
+    let result = (*context).inner.lock().submit_or_fail(&queue);
+    report(result.map(|aux| out_aux.write(aux)))
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2Dx12ContextBeginFrame(context: *mut Dx12Context, frame: *mut Frame) {
+    if !check_frame("lfx2Dx12ContextBeginFrame", context, frame) {
+        return;
+    }
     let frame = Arc::from_raw(frame);
     frame.mark(800, MarkType::Begin, timestamp_now());
     (*context).inner.lock().begin(&frame);
@@ -41,8 +112,24 @@ pub unsafe extern "C" fn lfx2Dx12ContextBeginFrame(context: *mut Dx12Context, fr
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2Dx12ContextEndFrame(context: *mut Dx12Context, frame: *mut Frame) {
+    if !check_frame("lfx2Dx12ContextEndFrame", context, frame) {
+        return;
+    }
     let frame = Arc::from_raw(frame);
     (*context).inner.lock().end(&frame);
     frame.mark(800, MarkType::End, timestamp_now());
     let _ = Arc::into_raw(frame);
 }
+
+unsafe fn check_frame(function: &str, context: *mut Dx12Context, frame: *mut Frame) -> bool {
+    handle::valid(function, context, HandleKind::Dx12Context)
+        && handle::valid(function, frame, HandleKind::Frame)
+        && handle::guard(
+            function,
+            handle::check_owner(
+                context,
+                HandleKind::Dx12Context,
+                Arc::as_ptr(&(*frame).context),
+            ),
+        )
+}
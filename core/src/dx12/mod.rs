@@ -11,6 +11,7 @@ use windows::Win32::Graphics::Dxgi::Common::{DXGI_FORMAT_UNKNOWN, DXGI_SAMPLE_DE
 use windows::Win32::System::Threading::{CreateEventW, WaitForSingleObject};
 use windows::Win32::System::WindowsProgramming::INFINITE;
 
+use crate::error::{Error, Result};
 use crate::{timestamp_from_qpc, Frame, Interval, MarkType, Timestamp};
 
 pub mod entrypoint;
@@ -32,6 +33,8 @@ struct Dx12ContextInner {
     // processing for events happened during that.
     current_frame: Option<Weak<Frame>>,
     current_frame_begun: bool,
+    /// Set after an error, after which no more timing work is submitted.
+    failed: bool,
 
     fence_thread: Option<JoinHandle<()>>,
     fence_tx: Option<mpsc::Sender<Dx12FenceMsg>>,
@@ -40,6 +43,7 @@ struct Dx12ContextInner {
     fence_value: u64,
 }
 
+/// Objects are null if there is nothing to submit, or if pacing was disabled after an error.
 #[repr(C)]
 #[derive(Default)]
 pub struct Dx12SubmitAux {
@@ -50,53 +54,50 @@ pub struct Dx12SubmitAux {
 }
 
 impl Dx12Context {
-    pub fn new(device: &ID3D12Device) -> Arc<Dx12Context> {
-        let device = device.cast::<ID3D12Device4>().unwrap();
+    pub fn new(device: &ID3D12Device) -> Result<Arc<Dx12Context>> {
+        let device = device.cast::<ID3D12Device4>()?;
         let context = Arc::new(Dx12Context {
-            inner: Mutex::new(Dx12ContextInner::new(device)),
+            inner: Mutex::new(Dx12ContextInner::new(device)?),
         });
         context
             .inner
             .lock()
-            .fence_tx
-            .as_mut()
-            .unwrap()
-            .send(Dx12FenceMsg::SetContext(Arc::downgrade(&context)))
-            .unwrap();
-        context
+            .send(Dx12FenceMsg::SetContext(Arc::downgrade(&context)))?;
+        Ok(context)
     }
 }
 
 impl Dx12ContextInner {
-    fn new(device: ID3D12Device4) -> Dx12ContextInner {
+    fn new(device: ID3D12Device4) -> Result<Dx12ContextInner> {
         let query_heap = unsafe {
             let mut query_heap = MaybeUninit::uninit();
-            device
-                .CreateQueryHeap(
-                    &D3D12_QUERY_HEAP_DESC {
-                        Type: D3D12_QUERY_HEAP_TYPE_TIMESTAMP,
-                        Count: 2,
-                        NodeMask: 0,
-                    },
-                    query_heap.as_mut_ptr(),
-                )
-                .unwrap();
-            query_heap.assume_init().unwrap()
+            device.CreateQueryHeap(
+                &D3D12_QUERY_HEAP_DESC {
+                    Type: D3D12_QUERY_HEAP_TYPE_TIMESTAMP,
+                    Count: 2,
+                    NodeMask: 0,
+                },
+                query_heap.as_mut_ptr(),
+            )?;
+            query_heap
+                .assume_init()
+                .ok_or(Error::Unavailable("query heap"))?
         };
 
-        let timestamp_command_list = [(); 2].map(|_| unsafe {
-            device
-                .CreateCommandList1(
+        let create_command_list = || -> windows::core::Result<ID3D12GraphicsCommandList> {
+            unsafe {
+                device.CreateCommandList1(
                     0,
                     D3D12_COMMAND_LIST_TYPE_DIRECT,
                     D3D12_COMMAND_LIST_FLAG_NONE,
                 )
-                .unwrap()
-        });
+            }
+        };
+        let timestamp_command_list = [create_command_list()?, create_command_list()?];
 
-        let fence: ID3D12Fence = unsafe { device.CreateFence(0, D3D12_FENCE_FLAG_NONE).unwrap() };
+        let fence: ID3D12Fence = unsafe { device.CreateFence(0, D3D12_FENCE_FLAG_NONE)? };
 
-        let event = unsafe { CreateEventW(None, false, false, None).unwrap() };
+        let event = unsafe { CreateEventW(None, false, false, None)? };
 
         let (fence_tx, fence_rx) = mpsc::channel();
         let mut fence_thread_ctx = Dx12FenceWorker {
@@ -108,7 +109,7 @@ impl Dx12ContextInner {
         };
         let fence_thread = thread::spawn(move || fence_thread_ctx.run());
 
-        Dx12ContextInner {
+        Ok(Dx12ContextInner {
             device,
             timestamp_command_list,
             command_allocator: vec![],
@@ -116,25 +117,28 @@ impl Dx12ContextInner {
             query_staging: vec![],
             current_frame: None,
             current_frame_begun: false,
+            failed: false,
             fence_thread: Some(fence_thread),
             fence_tx: Some(fence_tx),
             fence,
             fence_value: 1,
-        }
+        })
     }
 }
 
 impl Drop for Dx12ContextInner {
     fn drop(&mut self) {
         let _ = self.fence_tx.take();
-        self.fence_thread.take().unwrap().join().unwrap();
+        if let Some(fence_thread) = self.fence_thread.take() {
+            let _ = fence_thread.join();
+        }
     }
 }
 
 impl Dx12ContextInner {
-    fn get_query(&mut self) -> (ID3D12Resource, u32) {
+    fn get_query(&mut self) -> Result<(ID3D12Resource, u32)> {
         if let Some(q) = self.query_staging.pop() {
-            q
+            Ok(q)
         } else {
             let count = 16;
             let resource_desc = D3D12_RESOURCE_DESC {
@@ -158,68 +162,91 @@ impl Dx12ContextInner {
             };
             let buf: ID3D12Resource = unsafe {
                 let mut buf = MaybeUninit::uninit();
-                self.device
-                    .CreateCommittedResource(
-                        &heap_properties,
-                        D3D12_HEAP_FLAG_NONE,
-                        &resource_desc,
-                        D3D12_RESOURCE_STATE_COPY_DEST,
-                        None,
-                        buf.as_mut_ptr(),
-                    )
-                    .unwrap();
-                buf.assume_init().unwrap()
+                self.device.CreateCommittedResource(
+                    &heap_properties,
+                    D3D12_HEAP_FLAG_NONE,
+                    &resource_desc,
+                    D3D12_RESOURCE_STATE_COPY_DEST,
+                    None,
+                    buf.as_mut_ptr(),
+                )?;
+                buf.assume_init()
+                    .ok_or(Error::Unavailable("query readback buffer"))?
             };
             unsafe {
-                buf.Map(0, None, None).unwrap();
+                buf.Map(0, None, None)?;
             }
-            for i in 0..count {
+            for i in 1..count {
                 self.query_staging.push((buf.clone(), i));
             }
-            self.query_staging.pop().unwrap()
+            Ok((buf, 0))
         }
     }
 
-    fn get_allocator(&mut self) -> ID3D12CommandAllocator {
+    fn get_allocator(&mut self) -> Result<ID3D12CommandAllocator> {
         if let Some(a) = self.command_allocator.pop() {
-            a
+            Ok(a)
         } else {
             unsafe {
-                self.device
-                    .CreateCommandAllocator(D3D12_COMMAND_LIST_TYPE_DIRECT)
-                    .unwrap()
+                Ok(self
+                    .device
+                    .CreateCommandAllocator(D3D12_COMMAND_LIST_TYPE_DIRECT)?)
             }
         }
     }
 
+    fn send(&mut self, msg: Dx12FenceMsg) -> Result<()> {
+        self.fence_tx
+            .as_mut()
+            .ok_or(Error::WorkerGone)?
+            .send(msg)
+            .map_err(|_| Error::WorkerGone)
+    }
+
+    /// Disables pacing for the current frame's context. No more timing work is submitted.
+    fn fail(&mut self, frame: Option<&Arc<Frame>>, error: &Error) {
+        self.failed = true;
+        let current_frame = self.current_frame.as_ref().and_then(Weak::upgrade);
+        if let Some(frame) = frame.or(current_frame.as_ref()) {
+            frame.fail(error);
+        }
+    }
+
     fn begin(&mut self, frame: &Arc<Frame>) {
         let weak = Arc::downgrade(frame);
         self.current_frame = Some(weak.clone());
         self.current_frame_begun = false;
 
-        self.fence_tx
-            .as_mut()
-            .unwrap()
-            .send(Dx12FenceMsg::BeginFrame(weak))
-            .unwrap();
+        if let Err(e) = self.send(Dx12FenceMsg::BeginFrame(weak)) {
+            self.fail(Some(frame), &e);
+        }
     }
 
     fn end(&mut self, frame: &Arc<Frame>) {
         self.current_frame = None;
 
-        self.fence_tx
-            .as_mut()
-            .unwrap()
-            .send(Dx12FenceMsg::EndFrame(frame.clone()))
-            .unwrap();
+        if let Err(e) = self.send(Dx12FenceMsg::EndFrame(frame.clone())) {
+            self.fail(Some(frame), &e);
+        }
     }
 
-    fn submit(&mut self, queue: &ID3D12CommandQueue) -> Dx12SubmitAux {
+    /// Disables pacing if submitting fails. Afterwards, nothing is submitted anymore.
+    fn submit_or_fail(&mut self, queue: &ID3D12CommandQueue) -> Result<Dx12SubmitAux> {
+        if self.failed {
+            return Err(Error::Degraded);
+        }
+        self.submit(queue).map_err(|e| {
+            self.fail(None, &e);
+            e
+        })
+    }
+
+    fn submit(&mut self, queue: &ID3D12CommandQueue) -> Result<Dx12SubmitAux> {
         if self.current_frame.is_none() {
-            return Dx12SubmitAux::default();
+            return Ok(Dx12SubmitAux::default());
         }
 
-        let allocator = self.get_allocator();
+        let allocator = self.get_allocator()?;
 
         let build_timestamp_command_list = |command_list: &ID3D12GraphicsCommandList,
                                             query_heap: (&ID3D12QueryHeap, u32),
@@ -242,48 +269,42 @@ impl Dx12ContextInner {
         };
 
         let (execute_before, begin_query) = if !self.current_frame_begun {
-            let query = self.get_query();
+            let query = self.get_query()?;
             build_timestamp_command_list(
                 &self.timestamp_command_list[0],
                 (&self.query_heap, 0),
                 &query,
-            )
-            .unwrap();
+            )?;
             self.current_frame_begun = true;
             (Some(self.timestamp_command_list[0].clone()), Some(query))
         } else {
             (None, None)
         };
 
-        let end_query = self.get_query();
+        let end_query = self.get_query()?;
         build_timestamp_command_list(
             &self.timestamp_command_list[1],
             (&self.query_heap, 1),
             &end_query,
-        )
-        .unwrap();
+        )?;
 
         let fence_value = self.fence_value;
         self.fence_value += 1;
 
-        self.fence_tx
-            .as_mut()
-            .unwrap()
-            .send(Dx12FenceMsg::Wait(Dx12FenceWait {
-                queue: queue.clone(),
-                value: fence_value,
-                allocator,
-                begin_ts: begin_query,
-                end_ts: end_query,
-            }))
-            .unwrap();
-
-        Dx12SubmitAux {
+        self.send(Dx12FenceMsg::Wait(Dx12FenceWait {
+            queue: queue.clone(),
+            value: fence_value,
+            allocator,
+            begin_ts: begin_query,
+            end_ts: end_query,
+        }))?;
+
+        Ok(Dx12SubmitAux {
             execute_before,
             execute_after: Some(self.timestamp_command_list[1].clone()),
             signal_fence: Some(self.fence.clone()),
             signal_fence_value: fence_value,
-        }
+        })
     }
 }
 
@@ -333,7 +354,12 @@ impl Dx12FenceWorker {
                     });
                 }
                 Dx12FenceMsg::Wait(job) => {
-                    self.process_fence_wait(job).unwrap();
+                    if let Err(e) = self.process_fence_wait(job) {
+                        // Pacing can't continue without GPU timings.
+                        if let Some(frame) = self.tracker.take().and_then(|t| t.frame.upgrade()) {
+                            frame.fail(&e.into());
+                        }
+                    }
                 }
                 Dx12FenceMsg::EndFrame(frame) => {
                     self.process_end_frame(frame);
@@ -402,8 +428,13 @@ impl Dx12FenceWorker {
     }
 
     fn process_end_frame(&mut self, frame: Arc<Frame>) {
-        let tracker = self.tracker.take().unwrap();
-        assert_eq!(Arc::as_ptr(&frame), Weak::as_ptr(&tracker.frame));
+        let Some(tracker) = self
+            .tracker
+            .take()
+            .filter(|t| Arc::as_ptr(&frame) == Weak::as_ptr(&t.frame))
+        else {
+            return;
+        };
         if let Some(end_ts) = tracker.end_ts {
             frame.mark(1000, MarkType::End, end_ts);
         }
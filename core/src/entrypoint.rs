@@ -1,5 +1,15 @@
+use crate::config::{ContextConfig, PacingMode};
+use crate::error::{report, Error, ResultCode};
+use crate::estimator::EstimatorKind;
+use crate::handle::{self, HandleKind};
+use crate::profiler::{CallbackSink, ProfilerCallback, ProfilerRecord};
 use crate::time::{sleep_until, timestamp_now};
-use crate::{Context, Frame, ImplicitContext, Interval, MarkType, SectionId, Timestamp};
+use crate::{
+    Context, Frame, ImplicitContext, Interval, MarkDiagnostics, MarkType, SectionId, Timestamp,
+};
+use std::ffi::c_void;
+use std::os::raw::c_char;
+use std::ptr;
 use std::ptr::NonNull;
 use std::sync::Arc;
 
@@ -20,18 +30,329 @@ pub unsafe extern "C" fn lfx2SleepUntil(target: Timestamp) {
     sleep_until(target)
 }
 
+/// Returns the message of the last failing call on the calling thread.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2GetLastErrorMessage() -> *const c_char {
+    crate::error::last_error_message()
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn lfx2ContextCreate() -> *mut Context {
-    Arc::into_raw(Arc::new(Context::default())) as _
+    into_raw_context(Context::default())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextConfigDefault() -> ContextConfig {
+    ContextConfig::default()
+}
+
+fn into_raw_context(context: Context) -> *mut Context {
+    let context = Arc::into_raw(Arc::new(context));
+    handle::register(context, HandleKind::Context);
+    context as _
+}
+
+fn into_raw_frame(frame: Arc<Frame>) -> *mut Frame {
+    let frame = Arc::into_raw(frame);
+    handle::register(frame, HandleKind::Frame);
+    frame as _
+}
+
+/// Returns null if the config is null or invalid.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextCreateWithConfig(config: *const ContextConfig) -> *mut Context {
+    let Some(&config) = config.as_ref() else {
+        eprintln!("LFX2 WARN: Ignored call to lfx2ContextCreateWithConfig: config is null");
+        return ptr::null_mut();
+    };
+    if config.validate_handles {
+        handle::enable();
+    }
+    match Context::new(config) {
+        Ok(context) => into_raw_context(context),
+        Err(e) => {
+            eprintln!("LFX2 WARN: Rejected context config: {e}");
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Uses the default config if `config` is null.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextCreateChecked(
+    config: *const ContextConfig,
+    out_context: *mut *mut Context,
+) -> ResultCode {
+    if out_context.is_null() {
+        return report(Err(Error::InvalidArgument("out_context")));
+    }
+    *out_context = ptr::null_mut();
+    let config = config.as_ref().copied().unwrap_or_default();
+    if config.validate_handles {
+        handle::enable();
+    }
+    report(Context::new(config).map_err(Error::from).map(|context| {
+        *out_context = into_raw_context(context);
+    }))
+}
+
+/// Returns false and keeps the current config if the new config is null or invalid.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextSetConfig(
+    context: *mut Context,
+    config: *const ContextConfig,
+) -> bool {
+    if !handle::valid("lfx2ContextSetConfig", context, HandleKind::Context) {
+        return false;
+    }
+    let Some(&config) = config.as_ref() else {
+        eprintln!("LFX2 WARN: Ignored call to lfx2ContextSetConfig: config is null");
+        return false;
+    };
+    match (*context).set_config(config) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("LFX2 WARN: Rejected context config: {e}");
+            false
+        }
+    }
+}
+
+/// Returns false and keeps the current mode if the latency budget is out of range.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextSetMode(
+    context: *mut Context,
+    mode: PacingMode,
+    latency_budget: Interval,
+) -> bool {
+    if !handle::valid("lfx2ContextSetMode", context, HandleKind::Context) {
+        return false;
+    }
+    match (*context).set_mode(mode, latency_budget) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("LFX2 WARN: Rejected pacing mode: {e}");
+            false
+        }
+    }
+}
+
+/// Caps the frame rate in frames per second, or removes the cap if `frame_rate_limit` is zero.
+/// Returns false and keeps the current cap if the frame rate is out of range.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextSetFrameRateLimit(
+    context: *mut Context,
+    frame_rate_limit: f64,
+) -> bool {
+    if !handle::valid("lfx2ContextSetFrameRateLimit", context, HandleKind::Context) {
+        return false;
+    }
+    match (*context).set_frame_rate_limit(frame_rate_limit) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("LFX2 WARN: Rejected frame rate limit: {e}");
+            false
+        }
+    }
+}
+
+/// Aligns frames to the vblanks of a fixed-refresh display, or stops aligning them if
+/// `refresh_period` is zero. Vblanks must be reported with lfx2ContextReportVblank().
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextSetRefreshPeriod(
+    context: *mut Context,
+    refresh_period: Interval,
+) {
+    if !handle::valid("lfx2ContextSetRefreshPeriod", context, HandleKind::Context) {
+        return;
+    }
+    (*context).set_refresh_period(refresh_period);
+}
+
+/// Reports the timestamp of a vblank. Not every vblank needs to be reported.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextReportVblank(context: *mut Context, timestamp: Timestamp) {
+    if !handle::valid("lfx2ContextReportVblank", context, HandleKind::Context) {
+        return;
+    }
+    (*context).report_vblank(timestamp);
+}
+
+/// Keeps frames within the refresh range of a variable refresh rate display, or removes the
+/// constraint if both periods are zero. Returns false and keeps the current range if the periods
+/// are out of range.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextSetVrrRange(
+    context: *mut Context,
+    min_period: Interval,
+    max_period: Interval,
+) -> bool {
+    if !handle::valid("lfx2ContextSetVrrRange", context, HandleKind::Context) {
+        return false;
+    }
+    match (*context).set_vrr_range(min_period, max_period) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("LFX2 WARN: Rejected VRR range: {e}");
+            false
+        }
+    }
+}
+
+/// Stops pacing until lfx2ContextResume() is called, such as while a window is minimized. Frames
+/// created in the meantime start immediately and are not measured.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextPause(context: *mut Context) {
+    if !handle::valid("lfx2ContextPause", context, HandleKind::Context) {
+        return;
+    }
+    (*context).pause();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextResume(context: *mut Context) {
+    if !handle::valid("lfx2ContextResume", context, HandleKind::Context) {
+        return;
+    }
+    (*context).resume();
+}
+
+/// Returns true if pacing was disabled after an internal error. Frames then start immediately
+/// and their marks are ignored.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextIsDegraded(context: *mut Context) -> bool {
+    if !handle::valid("lfx2ContextIsDegraded", context, HandleKind::Context) {
+        return false;
+    }
+    (*context).is_degraded()
+}
+
+/// Returns the number of frames that were not released within `frame_timeout` and were ignored.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextGetLeakedFrameCount(context: *mut Context) -> u64 {
+    if !handle::valid(
+        "lfx2ContextGetLeakedFrameCount",
+        context,
+        HandleKind::Context,
+    ) {
+        return 0;
+    }
+    (*context).leaked_frames()
+}
+
+/// Returns the number of marks that were repaired or ignored because they were inconsistent with
+/// the rest of their frame or with the previous frame.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextGetMarkDiagnostics(context: *mut Context) -> MarkDiagnostics {
+    if !handle::valid(
+        "lfx2ContextGetMarkDiagnostics",
+        context,
+        HandleKind::Context,
+    ) {
+        return MarkDiagnostics::default();
+    }
+    (*context).mark_diagnostics()
+}
+
+/// Calls `callback` with every profiling event from now on, on the profiler thread. The callback
+/// must not call back into the context.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextAddProfilerCallback(
+    context: *mut Context,
+    callback: ProfilerCallback,
+    user_data: *mut c_void,
+) {
+    if !handle::valid(
+        "lfx2ContextAddProfilerCallback",
+        context,
+        HandleKind::Context,
+    ) {
+        return;
+    }
+    (*context).add_profiler_sink(Box::new(CallbackSink::new(callback, user_data)));
+}
+
+/// Keeps the latest `capacity` profiling events in memory, to be read with
+/// lfx2ContextReadProfilerRing(). Calls after the first one are ignored. Returns false if
+/// `capacity` exceeds 1048576 events.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextEnableProfilerRing(
+    context: *mut Context,
+    capacity: usize,
+) -> bool {
+    if !handle::valid(
+        "lfx2ContextEnableProfilerRing",
+        context,
+        HandleKind::Context,
+    ) {
+        return false;
+    }
+    match (*context).enable_profiler_ring(capacity) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("LFX2 WARN: Rejected profiler ring: {e}");
+            false
+        }
+    }
+}
+
+/// Moves up to `capacity` of the oldest events kept in memory to `out_records`, and returns how
+/// many were written.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextReadProfilerRing(
+    context: *mut Context,
+    out_records: *mut ProfilerRecord,
+    capacity: usize,
+) -> usize {
+    if !handle::valid("lfx2ContextReadProfilerRing", context, HandleKind::Context)
+        || out_records.is_null()
+    {
+        return 0;
+    }
+    let events = (*context).read_profiler_ring(capacity);
+    for (i, event) in events.iter().enumerate() {
+        out_records.add(i).write(ProfilerRecord::from(event));
+    }
+    events.len()
+}
+
+/// Replaces the throughput estimator of a section. The section's history is discarded when the
+/// kind changes.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2ContextSetSectionEstimator(
+    context: *mut Context,
+    section_id: SectionId,
+    kind: EstimatorKind,
+) {
+    if !handle::valid(
+        "lfx2ContextSetSectionEstimator",
+        context,
+        HandleKind::Context,
+    ) {
+        return;
+    }
+    (*context).set_section_estimator(section_id, kind);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2ContextAddRef(context: *mut Context) {
+    if !handle::guard(
+        "lfx2ContextAddRef",
+        handle::add_ref(context, HandleKind::Context),
+    ) {
+        return;
+    }
     Arc::increment_strong_count(context);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2ContextRelease(context: *mut Context) {
+    if !handle::guard(
+        "lfx2ContextRelease",
+        handle::release(context, HandleKind::Context),
+    ) {
+        return;
+    }
     Arc::decrement_strong_count(context);
 }
 
@@ -40,23 +361,71 @@ pub unsafe extern "C" fn lfx2FrameCreate(
     context: *mut Context,
     out_timestamp: *mut Timestamp,
 ) -> *mut Frame {
+    if !handle::valid("lfx2FrameCreate", context, HandleKind::Context) {
+        return ptr::null_mut();
+    }
+    let context = Arc::from_raw(context);
+    let (frame, timestamp) = context.create_frame(timestamp_now());
+    *out_timestamp = timestamp;
+    let _ = Arc::into_raw(context);
+    into_raw_frame(frame)
+}
+
+/// Returns `Degraded` along with an unpaced frame if pacing is disabled.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2FrameCreateChecked(
+    context: *mut Context,
+    out_frame: *mut *mut Frame,
+    out_timestamp: *mut Timestamp,
+) -> ResultCode {
+    if context.is_null() || out_frame.is_null() || out_timestamp.is_null() {
+        return report(Err(Error::InvalidArgument("null pointer")));
+    }
+    if let Err(e) = handle::check(context, HandleKind::Context) {
+        return report(Err(e));
+    }
     let context = Arc::from_raw(context);
-    let (frame, timestamp) = context.inner.lock().prepare_frame(context.clone());
+    let (frame, timestamp) = context.create_frame(timestamp_now());
+    *out_frame = into_raw_frame(frame);
     *out_timestamp = timestamp;
+    let degraded = context.is_degraded();
     let _ = Arc::into_raw(context);
-    Arc::into_raw(frame) as _
+    report(if degraded {
+        Err(Error::Degraded)
+    } else {
+        Ok(())
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2FrameAddRef(frame: *mut Frame) {
+    if !handle::guard("lfx2FrameAddRef", handle::add_ref(frame, HandleKind::Frame)) {
+        return;
+    }
     Arc::increment_strong_count(frame);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2FrameRelease(frame: *mut Frame) {
+    if !handle::guard(
+        "lfx2FrameRelease",
+        handle::release(frame, HandleKind::Frame),
+    ) {
+        return;
+    }
     Arc::decrement_strong_count(frame);
 }
 
+/// Marks a frame that will never be presented, such that it is not measured. The frame must
+/// still be released.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2FrameCancel(frame: *mut Frame) {
+    if !handle::valid("lfx2FrameCancel", frame, HandleKind::Frame) {
+        return;
+    }
+    (*frame).cancel();
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn lfx2MarkSection(
     frame: *mut Frame,
@@ -64,15 +433,39 @@ pub unsafe extern "C" fn lfx2MarkSection(
     mark_type: MarkType,
     timestamp: Timestamp,
 ) {
+    if !handle::valid("lfx2MarkSection", frame, HandleKind::Frame) {
+        return;
+    }
     (*frame).mark(section_id, mark_type, timestamp);
 }
 
+/// Like `lfx2MarkSection()`, but reports marks that were ignored because they are inconsistent
+/// with the rest of the frame, the frame is no longer tracked, or pacing is disabled.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2MarkSectionChecked(
+    frame: *mut Frame,
+    section_id: SectionId,
+    mark_type: MarkType,
+    timestamp: Timestamp,
+) -> ResultCode {
+    if frame.is_null() {
+        return report(Err(Error::InvalidArgument("frame")));
+    }
+    report(
+        handle::check(frame, HandleKind::Frame)
+            .and_then(|()| (*frame).try_mark(section_id, mark_type, timestamp)),
+    )
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn lfx2FrameOverrideQueuingDelay(
     frame: *mut Frame,
     section_id: SectionId,
     queueing_delay: Interval,
 ) {
+    if !handle::valid("lfx2FrameOverrideQueuingDelay", frame, HandleKind::Frame) {
+        return;
+    }
     (*frame).set_queueing_delay(section_id, queueing_delay);
 }
 
@@ -82,22 +475,43 @@ pub unsafe extern "C" fn lfx2FrameOverrideInverseThroughput(
     section_id: SectionId,
     inverse_throughput: Interval,
 ) {
+    if !handle::valid(
+        "lfx2FrameOverrideInverseThroughput",
+        frame,
+        HandleKind::Frame,
+    ) {
+        return;
+    }
     (*frame).set_inv_throughput(section_id, inverse_throughput);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2ImplicitContextCreate() -> *mut ImplicitContext {
-    let context = Box::new(ImplicitContext::default());
-    Box::into_raw(context)
+    let context = Box::into_raw(Box::new(ImplicitContext::default()));
+    handle::register(context, HandleKind::ImplicitContext);
+    context
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2ImplicitContextRelease(context: *mut ImplicitContext) {
+    if !handle::guard(
+        "lfx2ImplicitContextRelease",
+        handle::release(context, HandleKind::ImplicitContext),
+    ) {
+        return;
+    }
     let _ = Box::from_raw(context);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2ImplicitContextReset(context: *mut ImplicitContext) {
+    if !handle::valid(
+        "lfx2ImplicitContextReset",
+        context,
+        HandleKind::ImplicitContext,
+    ) {
+        return;
+    }
     (*context).reset();
 }
 
@@ -106,9 +520,16 @@ pub unsafe extern "C" fn lfx2FrameCreateImplicit(
     context: *mut ImplicitContext,
     out_timestamp: *mut Timestamp,
 ) -> *mut Frame {
+    if !handle::valid(
+        "lfx2FrameCreateImplicit",
+        context,
+        HandleKind::ImplicitContext,
+    ) {
+        return ptr::null_mut();
+    }
     let (frame, timestamp) = (*context).enqueue();
     *out_timestamp = timestamp;
-    Arc::into_raw(frame) as _
+    into_raw_frame(frame)
 }
 
 #[no_mangle]
@@ -116,6 +537,50 @@ pub unsafe extern "C" fn lfx2FrameDequeueImplicit(
     context: *mut ImplicitContext,
     critical: bool,
 ) -> Option<NonNull<Frame>> {
+    if !handle::valid(
+        "lfx2FrameDequeueImplicit",
+        context,
+        HandleKind::ImplicitContext,
+    ) {
+        return None;
+    }
     let frame = (*context).dequeue(critical);
-    frame.map(|f| NonNull::new(Arc::into_raw(f) as _).unwrap())
+    frame.and_then(|f| NonNull::new(into_raw_frame(f)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    unsafe fn last_error_message() -> &'static str {
+        CStr::from_ptr(lfx2GetLastErrorMessage()).to_str().unwrap()
+    }
+
+    #[test]
+    fn test_checked_rejects_null_out_pointer() {
+        unsafe {
+            let code = lfx2ContextCreateChecked(ptr::null(), ptr::null_mut());
+            assert_eq!(code, ResultCode::InvalidArgument);
+            assert_eq!(last_error_message(), "invalid out_context");
+        }
+    }
+
+    #[test]
+    fn test_checked_rejects_invalid_config() {
+        let config = ContextConfig {
+            beta: 2.,
+            ..Default::default()
+        };
+        let mut context = ptr::null_mut();
+        unsafe {
+            let code = lfx2ContextCreateChecked(&config, &mut context);
+            assert_eq!(code, ResultCode::InvalidArgument);
+            assert!(context.is_null());
+            assert_eq!(
+                last_error_message(),
+                "beta is out of range (expected 0 < beta <= 1)"
+            );
+        }
+    }
 }
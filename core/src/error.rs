@@ -0,0 +1,142 @@
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::c_char;
+
+use crate::config::ConfigError;
+use crate::handle::HandleError;
+
+/// Internal failure. Instead of aborting the application, it disables pacing for the context it
+/// happened in.
+#[derive(Debug)]
+pub enum Error {
+    /// A frame that was expected to be measured has no marks.
+    EmptyFrame,
+    /// A GPU timing worker has exited.
+    WorkerGone,
+    /// Pacing was disabled after an earlier error.
+    Degraded,
+    /// The frame was already retired, for example after timing out.
+    UntrackedFrame,
+    /// An argument passed by the application is invalid.
+    InvalidArgument(&'static str),
+    Config(ConfigError),
+    /// A handle passed by the application failed validation.
+    InvalidHandle(HandleError),
+    /// A required device feature is missing.
+    #[cfg(feature = "vulkan")]
+    Unsupported(String),
+    /// The driver returned no object and no error.
+    #[cfg(all(feature = "dx12", target_os = "windows"))]
+    Unavailable(&'static str),
+    #[cfg(feature = "vulkan")]
+    Vulkan(spark::vk::Result),
+    #[cfg(all(feature = "dx12", target_os = "windows"))]
+    Dx12(windows::core::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::EmptyFrame => write!(f, "frame has no marks"),
+            Error::WorkerGone => write!(f, "GPU timing worker has exited"),
+            Error::Degraded => write!(f, "pacing was disabled after an earlier error"),
+            Error::UntrackedFrame => write!(f, "frame is no longer tracked"),
+            Error::InvalidArgument(what) => write!(f, "invalid {what}"),
+            Error::Config(e) => write!(f, "{e}"),
+            Error::InvalidHandle(e) => write!(f, "invalid handle: {e}"),
+            #[cfg(feature = "vulkan")]
+            Error::Unsupported(what) => write!(f, "unsupported device: {what}"),
+            #[cfg(all(feature = "dx12", target_os = "windows"))]
+            Error::Unavailable(what) => write!(f, "{what} is unavailable"),
+            #[cfg(feature = "vulkan")]
+            Error::Vulkan(e) => write!(f, "Vulkan error {e:?}"),
+            #[cfg(all(feature = "dx12", target_os = "windows"))]
+            Error::Dx12(e) => write!(f, "D3D12 error {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Error::Config(e)
+    }
+}
+
+#[cfg(feature = "vulkan")]
+impl From<spark::vk::Result> for Error {
+    fn from(e: spark::vk::Result) -> Self {
+        Error::Vulkan(e)
+    }
+}
+
+#[cfg(all(feature = "dx12", target_os = "windows"))]
+impl From<windows::core::Error> for Error {
+    fn from(e: windows::core::Error) -> Self {
+        Error::Dx12(e)
+    }
+}
+
+/// Outcome of a fallible C API call. Details of a failure are available from
+/// `lfx2GetLastErrorMessage()`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResultCode {
+    Success,
+    /// Pacing is disabled for the context after an earlier error. Outputs are still valid, but
+    /// frames are not paced.
+    Degraded,
+    InvalidArgument,
+    /// The device lacks a required feature.
+    Unsupported,
+    /// The graphics API returned an error.
+    DeviceError,
+    InternalError,
+}
+
+impl Error {
+    pub fn code(&self) -> ResultCode {
+        match self {
+            Error::EmptyFrame | Error::WorkerGone => ResultCode::InternalError,
+            Error::Degraded => ResultCode::Degraded,
+            Error::UntrackedFrame
+            | Error::InvalidArgument(_)
+            | Error::Config(_)
+            | Error::InvalidHandle(_) => ResultCode::InvalidArgument,
+            #[cfg(feature = "vulkan")]
+            Error::Unsupported(_) => ResultCode::Unsupported,
+            #[cfg(all(feature = "dx12", target_os = "windows"))]
+            Error::Unavailable(_) => ResultCode::DeviceError,
+            #[cfg(feature = "vulkan")]
+            Error::Vulkan(_) => ResultCode::DeviceError,
+            #[cfg(all(feature = "dx12", target_os = "windows"))]
+            Error::Dx12(_) => ResultCode::DeviceError,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Converts the outcome of a C API call, recording the error message for the calling thread.
+pub fn report(result: Result<()>) -> ResultCode {
+    match result {
+        Ok(()) => ResultCode::Success,
+        Err(e) => {
+            let message = CString::new(e.to_string()).unwrap_or_default();
+            LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+            e.code()
+        }
+    }
+}
+
+/// Message of the last error reported on the calling thread. Valid until the next failing call
+/// on the same thread.
+pub fn last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ptr())
+}
@@ -0,0 +1,97 @@
+use crate::config::ContextConfig;
+use crate::ewma::EwmaEstimator;
+use crate::kalman::KalmanEstimator;
+use crate::quantile::QuantileEstimator;
+use crate::Interval;
+
+/// Tracks the typical value of a noisy series of samples.
+pub trait Estimator: Send {
+    /// Called when the config changes.
+    fn configure(&mut self, _config: &ContextConfig) {}
+
+    /// `elapsed` is the time since the previous sample, if known.
+    fn update(&mut self, v: f64, elapsed: Option<Interval>);
+
+    fn get(&self) -> f64;
+
+    /// Spread of the samples around the estimate.
+    fn variance(&self) -> f64;
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum EstimatorKind {
+    /// Exponentially weighted moving average with smoothing factor `beta`.
+    #[default]
+    Ewma,
+    /// 1-D Kalman filter that adapts its gain to the observed jitter and load changes.
+    Kalman,
+    /// The `quantile` of the last `quantile_window` samples, regardless of the time between them.
+    Quantile,
+}
+
+impl EstimatorKind {
+    pub fn create(self, config: &ContextConfig) -> Box<dyn Estimator> {
+        match self {
+            EstimatorKind::Ewma => Box::new(EwmaEstimator::with_time_constant(
+                config.beta,
+                config.estimator_time_constant,
+            )),
+            EstimatorKind::Kalman => Box::new(KalmanEstimator::new(config.estimator_time_constant)),
+            EstimatorKind::Quantile => Box::new(QuantileEstimator::new(
+                config.quantile,
+                config.quantile_window as usize,
+            )),
+        }
+    }
+}
+
+/// Smoothing factor for a sample arriving `elapsed` after the previous one.
+///
+/// With a time constant, the previous state decays by `exp(-elapsed / time_constant)`, so that the
+/// memory of the estimator spans the same wall-clock time regardless of the frame rate. Otherwise,
+/// or when the elapsed time is unknown, every sample is weighted by `per_sample`.
+pub fn smoothing_factor(
+    per_sample: f64,
+    time_constant: Interval,
+    elapsed: Option<Interval>,
+) -> f64 {
+    match elapsed {
+        Some(elapsed) if time_constant != 0 => {
+            1. - (-(elapsed as f64) / time_constant as f64).exp()
+        }
+        _ => per_sample,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time until the estimate covers 90% of a step, for samples `interval` apart.
+    fn settling_time(kind: EstimatorKind, interval: Interval) -> Interval {
+        let mut estimator = kind.create(&ContextConfig::default());
+        for _ in 0..1_000_000_000 / interval {
+            estimator.update(1_000_000., Some(interval));
+        }
+        let mut elapsed = 0;
+        while estimator.get() < 1_900_000. {
+            estimator.update(2_000_000., Some(interval));
+            elapsed += interval;
+        }
+        elapsed
+    }
+
+    #[test]
+    fn test_settling_time_is_frame_rate_independent() {
+        let (slow, fast) = (25_000_000, 8_333_333);
+        for kind in [EstimatorKind::Ewma, EstimatorKind::Kalman] {
+            let (slow_time, fast_time) = (settling_time(kind, slow), settling_time(kind, fast));
+            // Equal up to the granularity of the slower frame rate.
+            assert!(
+                slow_time.abs_diff(fast_time) <= slow,
+                "{kind:?}: {slow_time} at 40 fps, {fast_time} at 120 fps"
+            );
+        }
+    }
+}
@@ -1,28 +1,63 @@
+use crate::config::ContextConfig;
+use crate::estimator::{smoothing_factor, Estimator};
+use crate::Interval;
+
 pub struct EwmaEstimator {
     current: f64,
+    current_square: f64,
     current_weight: f64,
     alpha: f64,
+    time_constant: Interval,
 }
 
 impl EwmaEstimator {
     pub fn new(alpha: f64) -> EwmaEstimator {
         EwmaEstimator {
             current: 0.,
+            current_square: 0.,
             current_weight: 0.,
             alpha,
+            time_constant: 0,
         }
     }
 
-    pub fn update(&mut self, v: f64) {
-        self.current = (1. - self.alpha) * self.current + self.alpha * v;
-        self.current_weight = (1. - self.alpha) * self.current_weight + self.alpha;
+    /// Decays by `time_constant` nanoseconds of elapsed time instead of by `alpha` per sample,
+    /// when the time constant is non-zero.
+    pub fn with_time_constant(alpha: f64, time_constant: Interval) -> EwmaEstimator {
+        EwmaEstimator {
+            time_constant,
+            ..EwmaEstimator::new(alpha)
+        }
+    }
+}
+
+impl Estimator for EwmaEstimator {
+    fn configure(&mut self, config: &ContextConfig) {
+        self.alpha = config.beta;
+        self.time_constant = config.estimator_time_constant;
+    }
+
+    fn update(&mut self, v: f64, elapsed: Option<Interval>) {
+        let alpha = smoothing_factor(self.alpha, self.time_constant, elapsed);
+        self.current = (1. - alpha) * self.current + alpha * v;
+        self.current_square = (1. - alpha) * self.current_square + alpha * v * v;
+        self.current_weight = (1. - alpha) * self.current_weight + alpha;
     }
 
-    pub fn get(&self) -> f64 {
+    fn get(&self) -> f64 {
         if self.current_weight == 0. {
             0.
         } else {
             self.current / self.current_weight
         }
     }
+
+    fn variance(&self) -> f64 {
+        if self.current_weight == 0. {
+            0.
+        } else {
+            let mean = self.get();
+            (self.current_square / self.current_weight - mean * mean).max(0.)
+        }
+    }
 }
@@ -1,3 +1,4 @@
+use crate::error::{Error, Result};
 use crate::{Frame, Interval, MarkType, Timestamp};
 use std::sync::{mpsc, Arc, Weak};
 
@@ -7,7 +8,11 @@ pub struct FenceThread<S> {
 }
 
 impl<S: Send + 'static> FenceThread<S> {
-    pub fn new<F: FnMut(S) -> (Timestamp, Timestamp, Timestamp) + Send + 'static>(
+    /// `callback` waits for a submission and returns its submission, begin and end timestamps, or
+    /// `None` if the submission should be skipped.
+    pub fn new<
+        F: FnMut(S) -> Result<Option<(Timestamp, Timestamp, Timestamp)>> + Send + 'static,
+    >(
         callback: F,
     ) -> Self {
         let (tx, rx) = mpsc::channel();
@@ -24,19 +29,22 @@ impl<S: Send + 'static> FenceThread<S> {
         }
     }
 
-    pub fn send(&mut self, msg: FenceWorkerMessage<S>) {
-        self.tx.as_mut().unwrap().send(msg).unwrap();
+    pub fn send(&mut self, msg: FenceWorkerMessage<S>) -> Result<()> {
+        let tx = self.tx.as_mut().ok_or(Error::WorkerGone)?;
+        tx.send(msg).map_err(|_| Error::WorkerGone)
     }
 }
 
 impl<S> Drop for FenceThread<S> {
     fn drop(&mut self) {
         let _ = self.tx.take();
-        let _ = self.thread.take().unwrap().join();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
     }
 }
 
-struct FenceWorker<S, F: FnMut(S) -> (Timestamp, Timestamp, Timestamp)> {
+struct FenceWorker<S, F: FnMut(S) -> Result<Option<(Timestamp, Timestamp, Timestamp)>>> {
     rx: mpsc::Receiver<FenceWorkerMessage<S>>,
     tracker: Option<Tracker>,
 
@@ -59,7 +67,7 @@ pub enum FenceWorkerMessage<S> {
     EndFrame(Arc<Frame>),
 }
 
-impl<S, F: FnMut(S) -> (Timestamp, Timestamp, Timestamp)> FenceWorker<S, F> {
+impl<S, F: FnMut(S) -> Result<Option<(Timestamp, Timestamp, Timestamp)>>> FenceWorker<S, F> {
     fn run(&mut self) {
         while let Ok(job) = self.rx.recv() {
             match job {
@@ -73,7 +81,19 @@ impl<S, F: FnMut(S) -> (Timestamp, Timestamp, Timestamp)> FenceWorker<S, F> {
                     });
                 }
                 FenceWorkerMessage::Wait(job) => {
-                    let (submission_ts, begin_ts, end_ts) = (self.callback)(job);
+                    let (submission_ts, begin_ts, end_ts) = match (self.callback)(job) {
+                        Ok(Some(timestamps)) => timestamps,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            // Pacing can't continue without GPU timings.
+                            if let Some(frame) =
+                                self.tracker.take().and_then(|tr| tr.frame.upgrade())
+                            {
+                                frame.fail(&e);
+                            }
+                            continue;
+                        }
+                    };
                     if let Some(tr) = self.tracker.as_mut() {
                         tr.begin_ts =
                             Some(tr.begin_ts.map(|ts| ts.min(begin_ts)).unwrap_or(begin_ts));
@@ -93,8 +113,15 @@ impl<S, F: FnMut(S) -> (Timestamp, Timestamp, Timestamp)> FenceWorker<S, F> {
                     }
                 }
                 FenceWorkerMessage::EndFrame(frame) => {
-                    let tracker = self.tracker.take().unwrap();
-                    assert_eq!(Arc::as_ptr(&frame), Weak::as_ptr(&tracker.frame));
+                    // The application might end up mismatching call pairs, fail gracefully in
+                    // such cases.
+                    let Some(tracker) = self
+                        .tracker
+                        .take()
+                        .filter(|tr| Arc::as_ptr(&frame) == Weak::as_ptr(&tr.frame))
+                    else {
+                        continue;
+                    };
                     if let Some(begin_ts) = tracker.begin_ts {
                         frame.mark(1000, MarkType::Begin, begin_ts);
                     }
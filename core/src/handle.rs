@@ -0,0 +1,246 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+use parking_lot::Mutex;
+
+use crate::error::{Error, Result};
+
+/// Number of released handles remembered to detect use after release.
+const MAX_RELEASED: usize = 4096;
+
+/// Type of an object handed out through the C API.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HandleKind {
+    Context,
+    Frame,
+    ImplicitContext,
+    #[cfg(feature = "vulkan")]
+    VulkanContext,
+    #[cfg(all(feature = "dx12", target_os = "windows"))]
+    Dx12Context,
+}
+
+#[derive(Debug)]
+pub enum HandleError {
+    Released(HandleKind),
+    WrongKind {
+        expected: HandleKind,
+        actual: HandleKind,
+    },
+    /// A frame was passed to a graphics API context that is bound to another context.
+    ForeignFrame(HandleKind),
+}
+
+impl fmt::Display for HandleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandleError::Released(kind) => write!(f, "{kind:?} handle was already released"),
+            HandleError::WrongKind { expected, actual } => {
+                write!(f, "expected a {expected:?} handle, got a {actual:?} handle")
+            }
+            HandleError::ForeignFrame(kind) => {
+                write!(f, "frame belongs to a different context than this {kind:?}")
+            }
+        }
+    }
+}
+
+struct Entry {
+    kind: HandleKind,
+    /// References held by the application.
+    refs: usize,
+    /// Context whose frames a graphics API context handles, bound on the first frame.
+    owner: Option<usize>,
+}
+
+#[derive(Default)]
+struct Registry {
+    live: HashMap<usize, Entry>,
+    released: HashMap<usize, HandleKind>,
+    released_order: VecDeque<usize>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+/// Whether handles are validated. Validation is enabled by setting `LFX2_VALIDATE_HANDLES=1`, or
+/// through [`enable`].
+pub fn enabled() -> bool {
+    static FROM_ENV: Once = Once::new();
+    FROM_ENV.call_once(|| {
+        if std::env::var("LFX2_VALIDATE_HANDLES").as_deref() == Ok("1") {
+            enable();
+        }
+    });
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Enables validation for the rest of the process. Handles created before are not tracked, and
+/// are passed through unchecked.
+pub fn enable() {
+    REGISTRY.lock().get_or_insert_with(Registry::default);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn with_registry<T>(f: impl FnOnce(&mut Registry) -> Result<T>) -> Option<Result<T>> {
+    if !enabled() {
+        return None;
+    }
+    REGISTRY.lock().as_mut().map(f)
+}
+
+impl Registry {
+    /// Returns `None` for handles that are not tracked, such as those created before validation
+    /// was enabled.
+    fn entry(&mut self, handle: usize, kind: HandleKind) -> Result<Option<&mut Entry>> {
+        let error = match (self.live.get(&handle), self.released.get(&handle)) {
+            (Some(entry), _) if entry.kind != kind => HandleError::WrongKind {
+                expected: kind,
+                actual: entry.kind,
+            },
+            (Some(_), _) => return Ok(self.live.get_mut(&handle)),
+            (None, Some(&released)) if released == kind => HandleError::Released(kind),
+            (None, Some(&released)) => HandleError::WrongKind {
+                expected: kind,
+                actual: released,
+            },
+            (None, None) => return Ok(None),
+        };
+        Err(Error::InvalidHandle(error))
+    }
+}
+
+/// Records a reference handed to the application. The same object may be handed out more than
+/// once.
+pub fn register<T>(handle: *const T, kind: HandleKind) {
+    if handle.is_null() {
+        return;
+    }
+    let handle = handle as usize;
+    with_registry(|registry| {
+        registry.released.remove(&handle);
+        let entry = registry.live.entry(handle).or_insert(Entry {
+            kind,
+            refs: 0,
+            owner: None,
+        });
+        entry.kind = kind;
+        entry.refs += 1;
+        Ok(())
+    });
+}
+
+/// Checks that `handle` is not a released object or an object of another kind.
+pub fn check<T>(handle: *const T, kind: HandleKind) -> Result<()> {
+    with_registry(|registry| registry.entry(handle as usize, kind).map(|_| ())).unwrap_or(Ok(()))
+}
+
+pub fn add_ref<T>(handle: *const T, kind: HandleKind) -> Result<()> {
+    with_registry(|registry| {
+        if let Some(entry) = registry.entry(handle as usize, kind)? {
+            entry.refs += 1;
+        }
+        Ok(())
+    })
+    .unwrap_or(Ok(()))
+}
+
+/// Drops a reference held by the application, failing on double release.
+pub fn release<T>(handle: *const T, kind: HandleKind) -> Result<()> {
+    with_registry(|registry| {
+        let handle = handle as usize;
+        let Some(entry) = registry.entry(handle, kind)? else {
+            return Ok(());
+        };
+        entry.refs -= 1;
+        if entry.refs == 0 {
+            registry.live.remove(&handle);
+            registry.released.insert(handle, kind);
+            registry.released_order.push_back(handle);
+            if registry.released_order.len() > MAX_RELEASED {
+                let oldest = registry.released_order.pop_front().unwrap();
+                // The address might have been reused and released again since.
+                if !registry.released_order.contains(&oldest) {
+                    registry.released.remove(&oldest);
+                }
+            }
+        }
+        Ok(())
+    })
+    .unwrap_or(Ok(()))
+}
+
+/// Checks that a graphics API context only ever sees frames of one context, binding it to
+/// `context` on the first call.
+pub fn check_owner<T, C>(handle: *const T, kind: HandleKind, context: *const C) -> Result<()> {
+    with_registry(|registry| {
+        let Some(entry) = registry.entry(handle as usize, kind)? else {
+            return Ok(());
+        };
+        match *entry.owner.get_or_insert(context as usize) == context as usize {
+            true => Ok(()),
+            false => Err(Error::InvalidHandle(HandleError::ForeignFrame(kind))),
+        }
+    })
+    .unwrap_or(Ok(()))
+}
+
+/// Prints a warning if a call to `function` must be ignored because of an invalid handle.
+pub fn guard(function: &str, result: Result<()>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("LFX2 WARN: Ignored call to {function}: {e}");
+            false
+        }
+    }
+}
+
+/// Like [`guard`], for a handle that is used without changing its references.
+pub fn valid<T>(function: &str, handle: *const T, kind: HandleKind) -> bool {
+    guard(function, check(handle, kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reports_misuse() {
+        enable();
+        let objects = [0u8; 4];
+        let [context, frame, vulkan, untracked] = [0, 1, 2, 3].map(|i| &objects[i] as *const u8);
+
+        // Handles created before validation was enabled pass through.
+        assert!(check(untracked, HandleKind::Frame).is_ok());
+        assert!(release(untracked, HandleKind::Frame).is_ok());
+
+        register(context, HandleKind::Context);
+        register(frame, HandleKind::Frame);
+        assert!(check(context, HandleKind::Context).is_ok());
+        assert!(matches!(
+            check(frame, HandleKind::Context),
+            Err(Error::InvalidHandle(HandleError::WrongKind { .. }))
+        ));
+
+        add_ref(frame, HandleKind::Frame).unwrap();
+        release(frame, HandleKind::Frame).unwrap();
+        release(frame, HandleKind::Frame).unwrap();
+        assert!(matches!(
+            release(frame, HandleKind::Frame),
+            Err(Error::InvalidHandle(HandleError::Released(_)))
+        ));
+
+        #[cfg(feature = "vulkan")]
+        {
+            register(vulkan, HandleKind::VulkanContext);
+            check_owner(vulkan, HandleKind::VulkanContext, context).unwrap();
+            assert!(matches!(
+                check_owner(vulkan, HandleKind::VulkanContext, frame),
+                Err(Error::InvalidHandle(HandleError::ForeignFrame(_)))
+            ));
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::config::ContextConfig;
+use crate::estimator::{smoothing_factor, Estimator};
+use crate::Interval;
+
+/// Smoothing factor of the innovation statistics the noise estimates are derived from, unless a
+/// time constant is configured.
+const ADAPT_RATE: f64 = 0.1;
+/// Time constant of the innovation statistics, relative to a configured time constant. Matches
+/// `ADAPT_RATE` to an EWMA with the default `beta`, as both are tuned per sample.
+const ADAPT_TIME_SCALE: f64 = 3.4;
+
+/// 1-D Kalman filter over a random walk.
+///
+/// Both noise terms are estimated from the innovations (sample minus prediction): their running
+/// mean shows a shift of the underlying value and becomes the process noise, while their spread
+/// around that mean is jitter and becomes the measurement noise. A real load change therefore
+/// raises the gain within a few samples, while alternating jitter lowers it.
+#[derive(Default)]
+pub struct KalmanEstimator {
+    mean: Option<f64>,
+    variance: f64,
+    innovation_mean: f64,
+    innovation_square_mean: f64,
+    time_constant: Interval,
+}
+
+impl KalmanEstimator {
+    pub fn new(time_constant: Interval) -> KalmanEstimator {
+        KalmanEstimator {
+            time_constant,
+            ..Default::default()
+        }
+    }
+}
+
+impl Estimator for KalmanEstimator {
+    fn configure(&mut self, config: &ContextConfig) {
+        self.time_constant = config.estimator_time_constant;
+    }
+
+    fn update(&mut self, v: f64, elapsed: Option<Interval>) {
+        let mean = match self.mean {
+            Some(mean) => mean,
+            None => {
+                self.mean = Some(v);
+                return;
+            }
+        };
+
+        let time_constant = (self.time_constant as f64 * ADAPT_TIME_SCALE) as Interval;
+        let rate = smoothing_factor(ADAPT_RATE, time_constant, elapsed);
+        let innovation = v - mean;
+        self.innovation_mean += rate * (innovation - self.innovation_mean);
+        self.innovation_square_mean +=
+            rate * (innovation * innovation - self.innovation_square_mean);
+
+        let process_noise = self.innovation_mean * self.innovation_mean;
+        let measurement_noise = (self.innovation_square_mean - process_noise).max(0.);
+
+        let prior_variance = self.variance + process_noise;
+        let gain = if prior_variance + measurement_noise > 0. {
+            prior_variance / (prior_variance + measurement_noise)
+        } else {
+            1.
+        };
+        // The noise estimates are per sample. Scale the gain to the elapsed time, so that a step
+        // is followed in the same wall-clock time regardless of the frame rate.
+        let gain = 1. - (1. - gain).powf(rate / ADAPT_RATE);
+        self.mean = Some(mean + gain * innovation);
+        self.variance = (1. - gain) * prior_variance;
+    }
+
+    fn get(&self) -> f64 {
+        self.mean.unwrap_or(0.)
+    }
+
+    fn variance(&self) -> f64 {
+        // The jitter part of the innovations, i.e. the measurement noise.
+        (self.innovation_square_mean - self.innovation_mean * self.innovation_mean).max(0.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_TIME: f64 = 10_000_000.;
+
+    fn settled() -> KalmanEstimator {
+        let mut estimator = KalmanEstimator::new(0);
+        for _ in 0..30 {
+            estimator.update(FRAME_TIME, None);
+        }
+        estimator
+    }
+
+    #[test]
+    fn test_follows_step() {
+        let mut estimator = settled();
+        for _ in 0..6 {
+            estimator.update(1.5 * FRAME_TIME, None);
+        }
+        assert!(
+            (estimator.get() - 1.5 * FRAME_TIME).abs() < 0.02 * 1.5 * FRAME_TIME,
+            "{}",
+            estimator.get()
+        );
+    }
+
+    #[test]
+    fn test_filters_jitter() {
+        let mut estimator = settled();
+        let jitter = 0.1 * FRAME_TIME;
+        let mut estimates = Vec::new();
+        for i in 0..60 {
+            let sign = if i % 2 == 0 { 1. } else { -1. };
+            estimator.update(FRAME_TIME + sign * jitter, None);
+            estimates.push(estimator.get());
+        }
+        // Once the jitter has been learned, the estimate barely follows it.
+        let (min, max) = estimates[30..]
+            .iter()
+            .fold((f64::MAX, f64::MIN), |(min, max), &v| {
+                (min.min(v), max.max(v))
+            });
+        assert!(max - min < 0.05 * 2. * jitter, "{min} - {max}");
+        assert!((estimator.get() - FRAME_TIME).abs() < 0.05 * jitter);
+        assert!(estimator.variance() > 0.5 * jitter * jitter);
+    }
+}
@@ -1,27 +1,51 @@
 use parking_lot::Mutex;
+use std::cmp;
 use std::collections::{BTreeMap, VecDeque};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Once, Weak};
+use std::thread;
 use std::time::Duration;
-use std::{cmp, thread};
 
-use crate::ewma::EwmaEstimator;
-use crate::profiler::Profiler;
+use crate::config::{check, MAX_INTERVAL};
+use crate::display::DisplayPhase;
+use crate::error::{Error, Result};
+use crate::limiter::{frame_rate_interval, FrameLimiter};
+use crate::profiler::{Profiler, ProfilerConfig, RingReader, RingSink, MAX_RING_CAPACITY};
 use crate::time::*;
+use crate::vrr::{VrrRange, MIN_VRR_PERIOD};
 
+mod config;
+mod controller;
+mod display;
 #[cfg(all(feature = "dx12", target_os = "windows"))]
 mod dx12;
 mod entrypoint;
+mod error;
+mod estimator;
 mod ewma;
 mod fence_worker;
+mod handle;
+mod kalman;
+mod limiter;
+mod outlier;
+mod policy;
 mod profiler;
+mod quantile;
 mod time;
+mod vrr;
 #[cfg(feature = "vulkan")]
 mod vulkan;
 
-type SectionId = u32;
-type Timestamp = u64;
-type Interval = u64;
+pub use crate::config::{ConfigError, ContextConfig, ErrorController, PacingMode};
+pub use crate::estimator::EstimatorKind;
+pub use crate::policy::{
+    DefaultPolicy, FrameObservation, PacingDecision, PacingInput, PacingPolicy,
+};
+pub use crate::profiler::{BinaryReader, JsonSink, ProfilerEvent, ProfilerSink, Recorder};
+
+pub type SectionId = u32;
+pub type Timestamp = u64;
+pub type Interval = u64;
 
 #[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
 pub struct FrameId(u64);
@@ -33,34 +57,261 @@ pub enum MarkType {
     End,
 }
 
+/// Counts marks that were inconsistent with the rest of their frame or with the previous frame.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MarkDiagnostics {
+    /// Marks later than `mark_horizon` after the frame's predicted begin. They are ignored.
+    pub future: u64,
+    /// Repeated marks of the same section and type. They are ignored.
+    pub duplicate: u64,
+    /// Begin and End marks of a section that were in the wrong order. The later mark is moved to
+    /// the earlier one.
+    pub inverted: u64,
+    /// Begin marks earlier than the Begin of a preceding section. They are moved to it.
+    pub non_monotonic: u64,
+    /// Marks earlier than the same mark of the previous frame. They are moved to it.
+    pub out_of_order: u64,
+}
+
 #[derive(Default)]
 pub struct Context {
     inner: Mutex<ContextInner>,
 }
 
 struct ContextInner {
+    config: ContextConfig,
+    /// Mode last recorded to the profiler.
+    profiled_mode: Option<(PacingMode, Interval)>,
     next_frame_id: FrameId,
     frames: BTreeMap<FrameId, FrameImpl>,
     reference_frame: Option<FrameImpl>,
-    reference_delay: Option<i64>,
-    bandwidth_estimator: BTreeMap<SectionId, EwmaEstimator>,
+    policy: Box<dyn PacingPolicy>,
+    limiter: FrameLimiter,
+    paused: bool,
+    /// Number of frames reaped because they were not released within `frame_timeout`.
+    leaked_frames: u64,
+    mark_diagnostics: MarkDiagnostics,
+    /// Set after an internal error. Frames are no longer paced or measured.
+    degraded: bool,
+    display: Option<DisplayPhase>,
+    vrr: Option<VrrRange>,
 
     profiler: Profiler,
+    /// Set by [`Context::enable_profiler_ring`].
+    profiler_ring: Option<RingReader>,
 }
 
 impl Default for ContextInner {
     fn default() -> Self {
+        ContextInner::new(Profiler::new(&ProfilerConfig::from_env()))
+    }
+}
+
+impl ContextInner {
+    fn new(profiler: Profiler) -> Self {
         ContextInner {
+            config: ContextConfig::default(),
+            profiled_mode: None,
             next_frame_id: FrameId(0),
             frames: BTreeMap::new(),
             reference_frame: None,
-            reference_delay: None,
-            bandwidth_estimator: BTreeMap::new(),
-            profiler: Profiler::new(),
+            policy: Box::<DefaultPolicy>::default(),
+            limiter: FrameLimiter::default(),
+            paused: false,
+            leaked_frames: 0,
+            mark_diagnostics: MarkDiagnostics::default(),
+            degraded: false,
+            display: None,
+            vrr: None,
+            profiler,
+            profiler_ring: None,
         }
     }
 }
 
+impl Context {
+    pub fn new(config: ContextConfig) -> std::result::Result<Context, ConfigError> {
+        config.validate()?;
+        let profiler_config = ProfilerConfig::from_env();
+        let mut inner = ContextInner::new(Profiler::new(&ProfilerConfig {
+            enabled: profiler_config.enabled || config.profile,
+            ..profiler_config
+        }));
+        inner.set_config(config);
+        Ok(Context {
+            inner: Mutex::new(inner),
+        })
+    }
+
+    pub fn set_config(&self, config: ContextConfig) -> std::result::Result<(), ConfigError> {
+        config.validate()?;
+        self.inner.lock().set_config(config);
+        Ok(())
+    }
+
+    /// Replaces the pacing policy. State learned by the previous policy is discarded.
+    pub fn set_policy(&self, policy: Box<dyn PacingPolicy>) {
+        self.inner.lock().set_policy(policy);
+    }
+
+    pub fn set_mode(
+        &self,
+        mode: PacingMode,
+        latency_budget: Interval,
+    ) -> std::result::Result<(), ConfigError> {
+        let mut inner = self.inner.lock();
+        let config = ContextConfig {
+            mode,
+            latency_budget,
+            ..inner.config
+        };
+        config.validate()?;
+        inner.set_config(config);
+        Ok(())
+    }
+
+    /// Stops pacing until [`Context::resume`] is called, such as while a window is minimized.
+    /// Frames created in the meantime start immediately and are not measured.
+    pub fn pause(&self) {
+        let mut inner = self.inner.lock();
+        inner.paused = true;
+        inner.reset();
+    }
+
+    pub fn resume(&self) {
+        let mut inner = self.inner.lock();
+        inner.paused = false;
+        inner.reset();
+    }
+
+    /// Returns a handle for the next frame and the time it should start. After an internal
+    /// error, frames start immediately and their marks are ignored.
+    pub fn create_frame(self: &Arc<Self>, now: Timestamp) -> (Arc<Frame>, Timestamp) {
+        let mut inner = self.inner.lock();
+        if !inner.degraded {
+            match inner.prepare_frame(self.clone(), now) {
+                Ok(frame) => return frame,
+                Err(e) => inner.degrade(&e),
+            }
+        }
+        let id = inner.next_frame_id;
+        inner.next_frame_id.0 += 1;
+        (
+            Arc::new(Frame {
+                context: self.clone(),
+                id,
+            }),
+            now,
+        )
+    }
+
+    /// Whether pacing was disabled after an internal error.
+    pub fn is_degraded(&self) -> bool {
+        self.inner.lock().degraded
+    }
+
+    /// Number of frames that were not released within `frame_timeout` and were ignored.
+    pub fn leaked_frames(&self) -> u64 {
+        self.inner.lock().leaked_frames
+    }
+
+    /// Number of marks that were repaired or ignored, by kind of violation.
+    pub fn mark_diagnostics(&self) -> MarkDiagnostics {
+        self.inner.lock().mark_diagnostics
+    }
+
+    /// Passes profiling events to `sink` in addition to the existing sinks, from now on.
+    pub fn add_profiler_sink(&self, sink: Box<dyn ProfilerSink>) {
+        self.inner.lock().profiler.add_sink(sink);
+    }
+
+    /// Keeps the latest `capacity` profiling events in memory, to be read with
+    /// [`Context::read_profiler_ring`]. Does nothing if they are already kept.
+    pub fn enable_profiler_ring(&self, capacity: usize) -> std::result::Result<(), ConfigError> {
+        check(
+            capacity <= MAX_RING_CAPACITY,
+            "capacity",
+            "capacity <= 1048576",
+        )?;
+        let inner = &mut *self.inner.lock();
+        if inner.profiler_ring.is_none() {
+            let (sink, reader) = RingSink::new(capacity);
+            inner.profiler.add_sink(Box::new(sink));
+            inner.profiler_ring = Some(reader);
+        }
+        Ok(())
+    }
+
+    /// Removes and returns up to `max` of the oldest profiling events kept in memory. Events reach
+    /// the ring on the profiler thread, so the latest ones may not be visible yet.
+    pub fn read_profiler_ring(&self, max: usize) -> Vec<ProfilerEvent> {
+        match &self.inner.lock().profiler_ring {
+            Some(reader) => reader.take(max),
+            None => Vec::new(),
+        }
+    }
+
+    /// Caps the frame rate, or removes the cap if `frame_rate_limit` is zero.
+    pub fn set_frame_rate_limit(
+        &self,
+        frame_rate_limit: f64,
+    ) -> std::result::Result<(), ConfigError> {
+        let mut inner = self.inner.lock();
+        let config = ContextConfig {
+            frame_rate_limit,
+            ..inner.config
+        };
+        config.validate()?;
+        inner.set_config(config);
+        Ok(())
+    }
+
+    /// Aligns frames to the vblanks of a fixed-refresh display, or stops aligning them if
+    /// `refresh_period` is zero. Vblanks must be reported with [`Context::report_vblank`].
+    pub fn set_refresh_period(&self, refresh_period: Interval) {
+        let inner = &mut *self.inner.lock();
+        inner.display =
+            (refresh_period != 0).then(|| DisplayPhase::new(&inner.config, refresh_period));
+    }
+
+    pub fn report_vblank(&self, timestamp: Timestamp) {
+        if let Some(display) = &mut self.inner.lock().display {
+            display.vblank(timestamp);
+        }
+    }
+
+    /// Keeps frames within the refresh range of a variable refresh rate display, or removes the
+    /// constraint if both periods are zero.
+    pub fn set_vrr_range(
+        &self,
+        min_period: Interval,
+        max_period: Interval,
+    ) -> std::result::Result<(), ConfigError> {
+        let vrr = if (min_period, max_period) == (0, 0) {
+            None
+        } else {
+            check(
+                min_period > 0
+                    && min_period <= max_period
+                    && (MIN_VRR_PERIOD..=MAX_INTERVAL).contains(&max_period),
+                "vrr_range",
+                "0 < min_period <= max_period, 1ms <= max_period <= 10s",
+            )?;
+            Some(VrrRange::new(min_period, max_period))
+        };
+        self.inner.lock().vrr = vrr;
+        Ok(())
+    }
+
+    pub fn set_section_estimator(&self, section_id: SectionId, kind: EstimatorKind) {
+        let inner = &mut *self.inner.lock();
+        inner
+            .policy
+            .set_section_estimator(&inner.config, section_id, kind);
+    }
+}
+
 /// A write handle for frame markers.
 pub struct Frame {
     context: Arc<Context>,
@@ -71,6 +322,8 @@ struct FrameImpl {
     writer: Weak<Frame>,
     predicted_begin: u64,
     predicted_error_delta: i64,
+    /// Set for frames that will never be presented. They are retired without being measured.
+    cancelled: bool,
     marks: BTreeMap<(SectionId, MarkType), Timestamp>,
 
     // Overrides
@@ -79,53 +332,44 @@ struct FrameImpl {
 }
 
 impl ContextInner {
-    fn alpha(&self) -> f64 {
-        0.15
+    fn set_config(&mut self, config: ContextConfig) {
+        self.config = config;
+        self.policy.configure(&self.config);
+        if let Some(display) = &mut self.display {
+            display.configure(&self.config);
+        }
     }
 
-    fn beta(&self) -> f64 {
-        0.3
+    fn set_policy(&mut self, mut policy: Box<dyn PacingPolicy>) {
+        policy.configure(&self.config);
+        self.policy = policy;
     }
 
     fn frames_iter(&self) -> impl DoubleEndedIterator<Item = &FrameImpl> {
         self.reference_frame.iter().chain(self.frames.values())
     }
 
-    fn prepare_frame(&mut self, context: Arc<Context>) -> (Arc<Frame>, Timestamp) {
-        self.update_estimates();
+    fn prepare_frame(
+        &mut self,
+        context: Arc<Context>,
+        now: Timestamp,
+    ) -> Result<(Arc<Frame>, Timestamp)> {
+        self.update_estimates(now)?;
+        self.detect_gap(now);
 
-        let bias = 2_000_000;
-        let error = if let Some(actual) = self.reference_delay {
-            self.frames.iter().fold(actual, |acc, (_, frame)| {
-                (acc + frame.predicted_error_delta).max(0)
-            }) - bias
-        } else {
-            0
-        };
-
-        let clamped_error = error.clamp(-25_000_000, 25_000_000);
-
-        let now = timestamp_now();
-        let predicted_duration = self
-            .bandwidth_estimator
-            .iter()
-            .map(|(_, e)| e.get() as u64)
-            .max()
-            .unwrap_or(0);
-        let mut predicted_error_delta = -(self.alpha() * clamped_error as f64) as i64;
-        let target_frame_time = (predicted_duration as i64 - predicted_error_delta) as u64;
-
-        let last_frame_top = self.frames_iter().next_back().map(|f| f.predicted_begin);
-        let mut target;
-        if let Some(last_frame_top) = last_frame_top {
-            target = last_frame_top + target_frame_time;
-            if let Some(overdue) = now.checked_sub(last_frame_top + target_frame_time) {
-                target = now;
-                predicted_error_delta -= overdue as i64;
+        let PacingDecision {
+            target,
+            predicted_error_delta,
+            margin,
+        } = if self.paused {
+            PacingDecision {
+                target: now,
+                predicted_error_delta: 0,
+                margin: 0,
             }
         } else {
-            target = now;
-        }
+            self.pace(now)
+        };
 
         let id = self.next_frame_id;
         self.next_frame_id.0 += 1;
@@ -138,6 +382,8 @@ impl ContextInner {
                 writer: Arc::downgrade(&handle),
                 predicted_begin: target,
                 predicted_error_delta,
+                // Frames rendered while paused are not representative of the pipeline.
+                cancelled: self.paused,
                 marks: Default::default(),
                 inverse_throughput: Default::default(),
                 queueing_delay: Default::default(),
@@ -153,86 +399,305 @@ impl ContextInner {
         }
 
         self.profiler.sleep(id, now, target);
+        self.profiler.margin(margin, now);
+        let mode = (self.config.mode, self.config.latency_budget);
+        if self.profiled_mode != Some(mode) {
+            self.profiler.mode(mode.0, mode.1, now);
+            self.profiled_mode = Some(mode);
+        }
+
+        Ok((handle, target))
+    }
+
+    /// Records a mark after checking it against the rest of the frame and the previous frame.
+    /// Marks that would corrupt the estimates are moved to a consistent position or rejected.
+    /// Returns the recorded timestamp.
+    fn mark(
+        &mut self,
+        id: FrameId,
+        section_id: SectionId,
+        mark_type: MarkType,
+        mut timestamp: Timestamp,
+    ) -> Result<Timestamp> {
+        let horizon = self.config.mark_horizon;
+        let previous = self
+            .frames
+            .range(..id)
+            .rev()
+            .map(|(_, frame)| frame)
+            .find(|frame| !frame.cancelled)
+            .or(self.reference_frame.as_ref())
+            .and_then(|frame| frame.marks.get(&(section_id, mark_type)).copied());
+        let diagnostics = &mut self.mark_diagnostics;
+        let frame = self.frames.get_mut(&id).ok_or(Error::UntrackedFrame)?;
+
+        if timestamp > frame.predicted_begin.saturating_add(horizon) {
+            diagnostics.future += 1;
+            return Err(Error::InvalidArgument(
+                "mark timestamp, too far in the future",
+            ));
+        }
+        if frame.marks.contains_key(&(section_id, mark_type)) {
+            diagnostics.duplicate += 1;
+            return Err(Error::InvalidArgument("mark, already recorded"));
+        }
+        if let Some(previous) = previous.filter(|&previous| timestamp < previous) {
+            diagnostics.out_of_order += 1;
+            timestamp = previous;
+        }
+        match mark_type {
+            MarkType::Begin => {
+                let preceding = frame
+                    .marks
+                    .range(..(section_id, MarkType::Begin))
+                    .filter(|((_, mark_type), _)| *mark_type == MarkType::Begin)
+                    .map(|(_, &ts)| ts)
+                    .max();
+                if let Some(preceding) = preceding.filter(|&preceding| timestamp < preceding) {
+                    diagnostics.non_monotonic += 1;
+                    timestamp = preceding;
+                }
+                let end = frame.marks.get(&(section_id, MarkType::End)).copied();
+                if let Some(end) = end.filter(|&end| timestamp > end) {
+                    diagnostics.inverted += 1;
+                    timestamp = end;
+                }
+            }
+            MarkType::End => {
+                let begin = frame.marks.get(&(section_id, MarkType::Begin)).copied();
+                if let Some(begin) = begin.filter(|&begin| timestamp < begin) {
+                    diagnostics.inverted += 1;
+                    timestamp = begin;
+                }
+            }
+        }
+        frame.mark(section_id, mark_type, timestamp);
+        Ok(timestamp)
+    }
+
+    /// Disables pacing for good. Tracked frames are dropped, so that their marks are ignored.
+    fn degrade(&mut self, error: &Error) {
+        eprintln!("LFX2 WARN: Disabling pacing after an internal error: {error}");
+        self.degraded = true;
+        self.frames.clear();
+        self.reference_frame = None;
+    }
+
+    fn pace(&mut self, now: Timestamp) -> PacingDecision {
+        let in_flight_error_deltas = self
+            .frames
+            .values()
+            .filter(|frame| !frame.cancelled)
+            .map(|frame| frame.predicted_error_delta)
+            .collect::<Vec<_>>();
+        let min_frame_interval = self.min_frame_interval();
+        let frame_rate_slot = self.limiter.next_slot(min_frame_interval);
+        let input = PacingInput {
+            now,
+            last_predicted_begin: self.last_predicted_begin(),
+            in_flight_error_deltas: &in_flight_error_deltas,
+            frame_rate_slot,
+        };
+        let PacingDecision {
+            mut target,
+            mut predicted_error_delta,
+            margin,
+        } = self.policy.plan(&self.config, &input);
+        let mut earliest = input.frame_rate_slot.unwrap_or(target);
+        if let Some(display) = &self.display {
+            earliest = display.align(&self.config, cmp::max(target, earliest));
+        }
+        if earliest > target {
+            // Starting later drains the queue by the same amount.
+            predicted_error_delta -= (earliest - target) as i64;
+            target = earliest;
+        }
+        self.limiter.reserve(target);
+
+        PacingDecision {
+            target,
+            predicted_error_delta,
+            margin,
+        }
+    }
+
+    fn last_predicted_begin(&self) -> Option<Timestamp> {
+        self.frames_iter()
+            .rev()
+            .find(|frame| !frame.cancelled)
+            .map(|frame| frame.predicted_begin)
+    }
+
+    /// Resets the pacing state if no frame was requested for much longer than the frame time,
+    /// such as after a loading screen or a system suspend.
+    fn detect_gap(&mut self, now: Timestamp) {
+        if self.config.gap_ratio == 0. {
+            return;
+        }
+        let Some(last_predicted_begin) = self.last_predicted_begin() else {
+            return;
+        };
+        let frame_time = self.policy.frame_time().unwrap_or(0);
+        let threshold = cmp::max(
+            self.config.min_gap,
+            (self.config.gap_ratio * frame_time as f64) as Interval,
+        );
+        if now.saturating_sub(last_predicted_begin) > threshold {
+            self.reset();
+        }
+    }
 
-        (handle, target)
+    /// Forgets the state of the queue, while keeping what was learned about the pipeline. Frames
+    /// in flight straddle the discontinuity, so they are not measured.
+    fn reset(&mut self) {
+        for frame in self.frames.values_mut() {
+            frame.cancelled = true;
+        }
+        self.reference_frame = None;
+        self.limiter = FrameLimiter::default();
+        self.policy.reset();
     }
 
-    fn update_estimates(&mut self) {
-        const MAX_FRAME_TIME: u64 = 50_000_000;
-        const MAX_LATENCY: u64 = 200_000_000;
+    /// Minimum frame interval imposed by the frame rate cap and the display.
+    fn min_frame_interval(&mut self) -> Option<Interval> {
+        let vrr_interval = self
+            .vrr
+            .as_mut()
+            .map(|vrr| vrr.min_interval(&self.config, self.policy.frame_time()));
+        cmp::max(frame_rate_interval(&self.config), vrr_interval)
+    }
 
+    fn update_estimates(&mut self, now: Timestamp) -> Result<()> {
         while let Some(first) = self.frames.first_entry() {
             if first.get().writer.strong_count() != 0 {
-                break;
+                let timeout = self.config.frame_timeout;
+                if timeout == 0 || now.saturating_sub(first.get().predicted_begin) <= timeout {
+                    break;
+                }
+                // A leaked handle would otherwise block estimation forever.
+                let (frame_id, _) = first.remove_entry();
+                self.leaked_frames += 1;
+                // Leaks tend to repeat every frame, so only the first one is reported. The rest
+                // are counted in `leaked_frames`.
+                if self.leaked_frames == 1 {
+                    eprintln!(
+                        "LFX2 WARN: Frame {} was not released within {}ms and is ignored. Did you forget to call lfx2FrameRelease()? Further leaks are only counted.",
+                        frame_id.0,
+                        timeout / 1_000_000
+                    );
+                }
+                continue;
             }
 
             let (frame_id, frame) = first.remove_entry();
+            if frame.cancelled || frame.marks.is_empty() {
+                continue;
+            }
 
-            if let Some(reference_frame) = &self.reference_frame {
-                let queueing_delay = frame.queueing_delay(reference_frame);
-                // Should not overflow, but for sanity
-                let real_latency = frame.end_ts().saturating_sub(frame.begin_ts());
+            let (queueing_delay, frame_interval) =
+                if let Some(reference_frame) = &self.reference_frame {
+                    let queueing_delay = frame.queueing_delay(reference_frame);
+                    // Should not overflow, but for sanity
+                    let real_latency = frame.end_ts()?.saturating_sub(frame.begin_ts()?);
+                    let frame_interval = frame
+                        .begin_ts()?
+                        .saturating_sub(reference_frame.begin_ts()?);
 
-                self.reference_delay = Some(queueing_delay as i64);
+                    if let Some(display) = &mut self.display {
+                        display.observe_latency(real_latency, Some(frame_interval));
+                    }
 
-                self.profiler
-                    .latency(frame_id, real_latency, queueing_delay, frame.end_ts());
+                    self.profiler
+                        .latency(frame_id, real_latency, queueing_delay, frame.end_ts()?);
 
-                self.profiler.frame_time(
-                    frame_id,
-                    frame.begin_ts() - reference_frame.begin_ts(),
-                    frame.end_ts() - reference_frame.end_ts(),
-                    frame.end_ts(),
-                );
-            }
+                    self.profiler.frame_time(
+                        frame_id,
+                        frame_interval,
+                        frame.end_ts()?.saturating_sub(reference_frame.end_ts()?),
+                        frame.end_ts()?,
+                    );
 
-            for (section_id, duration) in frame.inverse_throughput().into_iter() {
-                let duration = frame
-                    .inverse_throughput
-                    .get(&section_id)
-                    .map(|x| *x)
-                    .unwrap_or(duration);
-                let beta = self.beta();
-                self.bandwidth_estimator
-                    .entry(section_id)
-                    .or_insert_with(|| EwmaEstimator::new(beta))
-                    .update(cmp::min(duration, MAX_FRAME_TIME) as f64);
-            }
+                    (Some(queueing_delay), Some(frame_interval))
+                } else {
+                    (None, None)
+                };
+
+            self.policy.observe(
+                &self.config,
+                &FrameObservation {
+                    frame_id,
+                    queueing_delay,
+                    frame_interval,
+                    inverse_throughput: &frame.inverse_throughput(),
+                    end_ts: frame.marks.last_key_value().map(|(_, &ts)| ts),
+                },
+                &mut self.profiler.recorder(),
+            );
 
             self.reference_frame = Some(frame);
         }
+        Ok(())
     }
 }
 
+// Frames that were reaped after timing out are no longer tracked, so updates to them are dropped.
 impl Frame {
     fn mark(&self, section_id: SectionId, mark_type: MarkType, timestamp: Timestamp) {
+        let _ = self.try_mark(section_id, mark_type, timestamp);
+    }
+
+    fn try_mark(
+        &self,
+        section_id: SectionId,
+        mark_type: MarkType,
+        timestamp: Timestamp,
+    ) -> Result<()> {
         let mut inner = self.context.inner.lock();
-        inner
-            .frames
-            .get_mut(&self.id)
-            .unwrap()
-            .mark(section_id, mark_type, timestamp);
+        if inner.degraded {
+            return Err(Error::Degraded);
+        }
+        let diagnostics = inner.mark_diagnostics;
+        let result = inner.mark(self.id, section_id, mark_type, timestamp);
+        if inner.mark_diagnostics != diagnostics {
+            static MARK_WARN: Once = Once::new();
+            MARK_WARN.call_once(|| {
+                eprintln!("LFX2 WARN: Inconsistent marks were repaired or ignored. See lfx2ContextGetMarkDiagnostics().");
+            });
+        }
+        let timestamp = result?;
         inner
             .profiler
             .mark(self.id, section_id, mark_type, timestamp);
+        Ok(())
+    }
+
+    /// Disables pacing for the frame's context after an internal error.
+    fn fail(&self, error: &Error) {
+        let mut inner = self.context.inner.lock();
+        if !inner.degraded {
+            inner.degrade(error);
+        }
+    }
+
+    fn cancel(&self) {
+        let mut inner = self.context.inner.lock();
+        if let Some(frame) = inner.frames.get_mut(&self.id) {
+            frame.cancelled = true;
+        }
     }
 
     fn set_inv_throughput(&self, section_id: SectionId, inv_throughput: Interval) {
         let mut inner = self.context.inner.lock();
-        inner
-            .frames
-            .get_mut(&self.id)
-            .unwrap()
-            .set_inv_throughput(section_id, inv_throughput);
+        if let Some(frame) = inner.frames.get_mut(&self.id) {
+            frame.set_inv_throughput(section_id, inv_throughput);
+        }
     }
 
     fn set_queueing_delay(&self, section_id: SectionId, queueing_delay: Interval) {
         let mut inner = self.context.inner.lock();
-        inner
-            .frames
-            .get_mut(&self.id)
-            .unwrap()
-            .set_queueing_delay(section_id, queueing_delay);
+        if let Some(frame) = inner.frames.get_mut(&self.id) {
+            frame.set_queueing_delay(section_id, queueing_delay);
+        }
     }
 }
 
@@ -253,12 +718,18 @@ fn filter_marks_by_type(
 }
 
 impl FrameImpl {
-    fn begin_ts(&self) -> Timestamp {
-        self.marks.first_key_value().map(|x| *x.1).unwrap()
+    fn begin_ts(&self) -> Result<Timestamp> {
+        self.marks
+            .first_key_value()
+            .map(|x| *x.1)
+            .ok_or(Error::EmptyFrame)
     }
 
-    fn end_ts(&self) -> Timestamp {
-        self.marks.last_key_value().map(|x| *x.1).unwrap()
+    fn end_ts(&self) -> Result<Timestamp> {
+        self.marks
+            .last_key_value()
+            .map(|x| *x.1)
+            .ok_or(Error::EmptyFrame)
     }
 
     fn mark(&mut self, section_id: SectionId, mark_type: MarkType, timestamp: Timestamp) {
@@ -341,9 +812,7 @@ impl ImplicitContext {
             self.inner.lock()
         };
 
-        let mut context = inner.context.inner.lock();
-        let (frame, timestamp) = context.prepare_frame(inner.context.clone());
-        drop(context);
+        let (frame, timestamp) = inner.context.create_frame(timestamp_now());
         inner.frame_queue.push_back(frame.clone());
 
         if inner.frame_queue.len() > RENDER_DESYNC_THRESHOLD {
@@ -377,3 +846,539 @@ impl ImplicitContext {
         self.need_reset.store(true, Ordering::SeqCst);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ErrorController;
+    use std::{cmp, io};
+
+    const CPU: SectionId = 0;
+    const GPU: SectionId = 1000;
+    /// Frames the GPU can have queued before presentation blocks the CPU.
+    const MAX_QUEUED_FRAMES: usize = 2;
+
+    struct Stats {
+        frame_interval: f64,
+        latency: f64,
+    }
+
+    /// Drives a context with a CPU -> GPU pipeline where the GPU is the bottleneck, and returns
+    /// the steady-state frame interval and CPU begin to GPU end latency.
+    fn simulate(
+        config: ContextConfig,
+        cpu_time: Interval,
+        gpu_time: Interval,
+        frames: usize,
+    ) -> Stats {
+        let mut inner = ContextInner::new(Profiler::with_writer(io::sink()));
+        inner.set_config(config);
+        let context = Arc::new(Context {
+            inner: Mutex::new(inner),
+        });
+        let mut now: Timestamp = 1_000_000_000_000;
+        let mut gpu_free = now;
+        let mut in_flight: VecDeque<(Arc<Frame>, Timestamp)> = VecDeque::new();
+        let mut gpu_ends = Vec::new();
+        let mut latencies = Vec::new();
+
+        for _ in 0..frames {
+            while in_flight.front().is_some_and(|&(_, end)| end <= now) {
+                in_flight.pop_front();
+            }
+
+            let (frame, target) = context.create_frame(now);
+            assert!(target >= now);
+
+            let cpu_begin = target;
+            let cpu_end = cpu_begin + cpu_time;
+            let gpu_begin = cmp::max(cpu_end, gpu_free);
+            let gpu_end = gpu_begin + gpu_time;
+            gpu_free = gpu_end;
+
+            frame.mark(CPU, MarkType::Begin, cpu_begin);
+            frame.mark(CPU, MarkType::End, cpu_end);
+            frame.mark(GPU, MarkType::Begin, gpu_begin);
+            frame.mark(GPU, MarkType::End, gpu_end);
+            in_flight.push_back((frame, gpu_end));
+            gpu_ends.push(gpu_end);
+            latencies.push(gpu_end - cpu_begin);
+
+            now = cpu_end;
+            if in_flight.len() >= MAX_QUEUED_FRAMES {
+                now = cmp::max(now, in_flight[in_flight.len() - MAX_QUEUED_FRAMES].1);
+            }
+        }
+
+        let steady = frames / 2;
+        let frame_interval =
+            (gpu_ends[frames - 1] - gpu_ends[steady]) as f64 / (frames - 1 - steady) as f64;
+        let latency =
+            latencies[steady..].iter().sum::<Interval>() as f64 / (frames - steady) as f64;
+        Stats {
+            frame_interval,
+            latency,
+        }
+    }
+
+    fn check_regime(cpu_time: Interval, gpu_time: Interval) {
+        let config = ContextConfig::default();
+        let stats = simulate(config, cpu_time, gpu_time, 2000);
+
+        // Pacing must not cost throughput...
+        let throughput_loss = stats.frame_interval / gpu_time as f64 - 1.;
+        assert!(
+            throughput_loss < 0.02,
+            "frame interval {}",
+            stats.frame_interval
+        );
+        // ...and must keep the GPU queue short. A steady pipeline only needs the minimum margin.
+        let ideal_latency = (cpu_time + gpu_time + config.min_margin) as f64;
+        assert!(
+            stats.latency < ideal_latency + gpu_time as f64 * 0.1,
+            "latency {}",
+            stats.latency
+        );
+    }
+
+    #[test]
+    fn test_5fps() {
+        check_regime(100_000_000, 200_000_000);
+    }
+
+    #[test]
+    fn test_60fps() {
+        check_regime(8_000_000, 16_666_667);
+    }
+
+    #[test]
+    fn test_2000fps() {
+        check_regime(300_000, 500_000);
+    }
+
+    #[test]
+    fn test_balanced_mode() {
+        let (cpu_time, gpu_time) = (8_000_000, 16_666_667);
+        let config = ContextConfig {
+            mode: PacingMode::Balanced,
+            latency_budget: 6_000_000,
+            ..Default::default()
+        };
+        let stats = simulate(config, cpu_time, gpu_time, 2000);
+
+        let throughput_loss = stats.frame_interval / gpu_time as f64 - 1.;
+        assert!(
+            throughput_loss < 0.02,
+            "frame interval {}",
+            stats.frame_interval
+        );
+        // The queue is allowed to grow up to the budget, but not further.
+        let budget_latency = (cpu_time + gpu_time + config.latency_budget) as f64;
+        assert!(
+            (stats.latency - budget_latency).abs() < gpu_time as f64 * 0.1,
+            "latency {}",
+            stats.latency
+        );
+    }
+
+    #[test]
+    fn test_frame_rate_cap() {
+        let (cpu_time, gpu_time) = (8_000_000, 16_666_667);
+        for error_controller in [ErrorController::Proportional, ErrorController::Pid] {
+            let config = ContextConfig {
+                frame_rate_limit: 30.,
+                error_controller,
+                ..Default::default()
+            };
+            let stats = simulate(config, cpu_time, gpu_time, 2000);
+
+            assert!(
+                (stats.frame_interval - 1e9 / 30.).abs() < 100_000.,
+                "frame interval {}",
+                stats.frame_interval
+            );
+            // Frames held back by the cap never queue up behind each other.
+            assert!(
+                stats.latency < (cpu_time + gpu_time) as f64 + 100_000.,
+                "latency {}",
+                stats.latency
+            );
+        }
+    }
+
+    #[test]
+    fn test_frames_complete_before_vblank() {
+        let refresh_period: Interval = 16_666_667;
+        let (cpu_time, gpu_time) = (2_000_000, 5_000_000);
+        let config = ContextConfig::default();
+        let context = Arc::new(Context {
+            inner: Mutex::new(ContextInner::new(Profiler::with_writer(io::sink()))),
+        });
+        context.set_refresh_period(refresh_period);
+        let start: Timestamp = 1_000_000_000_000;
+        let vblank_at = |i: u64| start + i * refresh_period;
+        let mut next_vblank = 0;
+        let mut now = start;
+        let mut slack = Vec::new();
+
+        for _ in 0..300 {
+            while vblank_at(next_vblank) <= now {
+                context.report_vblank(vblank_at(next_vblank));
+                next_vblank += 1;
+            }
+            let (frame, target) = context.create_frame(now);
+            let completion = target + cpu_time + gpu_time;
+            frame.mark(CPU, MarkType::Begin, target);
+            frame.mark(CPU, MarkType::End, target + cpu_time);
+            frame.mark(GPU, MarkType::Begin, target + cpu_time);
+            frame.mark(GPU, MarkType::End, completion);
+            // The frame is presented on the first vblank after it completes, which is also when
+            // the next frame can begin.
+            let presented = (next_vblank..)
+                .map(vblank_at)
+                .find(|&vblank| vblank >= completion)
+                .unwrap();
+            slack.push(presented - completion);
+            now = presented;
+        }
+
+        for &slack in &slack[150..] {
+            assert!(
+                slack.abs_diff(config.vblank_slack) < 100_000,
+                "completed {slack}ns before vblank"
+            );
+        }
+    }
+
+    /// Starts frames at a fixed interval, using only the public API.
+    struct FixedIntervalPolicy {
+        interval: crate::Interval,
+        observed: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl crate::PacingPolicy for FixedIntervalPolicy {
+        fn observe(
+            &mut self,
+            _config: &crate::ContextConfig,
+            _observation: &crate::FrameObservation,
+            _recorder: &mut crate::Recorder,
+        ) {
+            self.observed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn plan(
+            &mut self,
+            _config: &crate::ContextConfig,
+            input: &crate::PacingInput,
+        ) -> crate::PacingDecision {
+            let target = input
+                .last_predicted_begin
+                .map_or(input.now, |last| cmp::max(input.now, last + self.interval));
+            crate::PacingDecision {
+                target,
+                predicted_error_delta: 0,
+                margin: 0,
+            }
+        }
+    }
+
+    #[test]
+    fn test_policy_can_be_replaced() {
+        let context = Arc::new(Context {
+            inner: Mutex::new(ContextInner::new(Profiler::with_writer(io::sink()))),
+        });
+        let mut now: Timestamp = 1_000_000_000_000;
+        let mut run_frames = |count: usize| {
+            let mut targets = Vec::new();
+            for _ in 0..count {
+                let (frame, target) = context.create_frame(now);
+                frame.mark(CPU, MarkType::Begin, target);
+                frame.mark(CPU, MarkType::End, target + 1_000_000);
+                now = target + 1_000_000;
+                targets.push(target);
+            }
+            targets
+        };
+        run_frames(10);
+
+        let observed = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        context.set_policy(Box::new(FixedIntervalPolicy {
+            interval: 10_000_000,
+            observed: observed.clone(),
+        }));
+        let targets = run_frames(10);
+        for pair in targets[1..].windows(2) {
+            assert_eq!(pair[1] - pair[0], 10_000_000);
+        }
+        assert!(observed.load(Ordering::Relaxed) >= 8);
+    }
+
+    #[test]
+    fn test_cancelled_frame() {
+        let context = Arc::new(Context {
+            inner: Mutex::new(ContextInner::new(Profiler::with_writer(io::sink()))),
+        });
+        let now: Timestamp = 1_000_000_000_000;
+
+        let (frame, _) = context.create_frame(now);
+        frame.mark(CPU, MarkType::Begin, now);
+        frame.mark(CPU, MarkType::End, now + 1_000_000);
+        drop(frame);
+        // Cancelled after being marked.
+        let (frame, _) = context.create_frame(now + 2_000_000);
+        frame.mark(CPU, MarkType::Begin, now + 2_000_000);
+        frame.cancel();
+        drop(frame);
+        // Released without any mark.
+        let (frame, _) = context.create_frame(now + 3_000_000);
+        drop(frame);
+        let (frame, _) = context.create_frame(now + 4_000_000);
+        drop(frame);
+
+        let inner = context.inner.lock();
+        assert_eq!(
+            inner.reference_frame.as_ref().unwrap().begin_ts().unwrap(),
+            now
+        );
+    }
+
+    /// Runs a single-section pipeline whose frame time alternates by `jitter`, and returns the
+    /// last margin reported to the profiler.
+    fn profiled_margin(config: ContextConfig, jitter: Interval) -> Interval {
+        let (ring, reader) = RingSink::new(4096);
+        let mut profiler = Profiler::disabled();
+        profiler.add_sink(Box::new(ring));
+        let mut inner = ContextInner::new(profiler);
+        inner.set_config(config);
+        let context = Arc::new(Context {
+            inner: Mutex::new(inner),
+        });
+        let mut now: Timestamp = 1_000_000_000_000;
+        for i in 0..200 {
+            let (frame, target) = context.create_frame(now);
+            let frame_time = 10_000_000 + jitter - i % 2 * 2 * jitter;
+            frame.mark(CPU, MarkType::Begin, target);
+            frame.mark(CPU, MarkType::End, target + frame_time);
+            now = target + frame_time;
+        }
+        drop(context);
+
+        reader
+            .take(usize::MAX)
+            .into_iter()
+            .rev()
+            .find_map(|event| match event {
+                ProfilerEvent::Margin { margin, .. } => Some(margin),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_margin_follows_jitter() {
+        let config = ContextConfig {
+            min_margin: 0,
+            ..Default::default()
+        };
+        // A steady pipeline needs no margin beyond `min_margin`.
+        assert!(profiled_margin(config, 0) < 10_000);
+        // Alternating by `jitter` is a standard deviation of `jitter`.
+        for jitter in [250_000, 1_000_000] {
+            let margin = profiled_margin(config, jitter) as f64;
+            let expected = config.margin_sigma * jitter as f64;
+            assert!(
+                (margin / expected - 1.).abs() < 0.1,
+                "jitter {jitter}: margin {margin}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_gap_resets_reference() {
+        let context = Arc::new(Context {
+            inner: Mutex::new(ContextInner::new(Profiler::with_writer(io::sink()))),
+        });
+        let mut now: Timestamp = 1_000_000_000_000;
+        for _ in 0..10 {
+            let (frame, target) = context.create_frame(now);
+            frame.mark(CPU, MarkType::Begin, target);
+            frame.mark(CPU, MarkType::End, target + 10_000_000);
+            now = target + 10_000_000;
+        }
+
+        now += 5_000_000_000;
+        let (_frame, target) = context.create_frame(now);
+        assert_eq!(target, now);
+        assert!(context.inner.lock().reference_frame.is_none());
+    }
+
+    #[test]
+    fn test_pause_keeps_estimates() {
+        let (cpu_time, gpu_time) = (2_000_000, 10_000_000);
+        let context = Arc::new(Context {
+            inner: Mutex::new(ContextInner::new(Profiler::with_writer(io::sink()))),
+        });
+        let frame_time = || context.inner.lock().policy.frame_time();
+        // Renders a frame requested at `now`, returning its target and when the CPU is done.
+        let render = |now: Timestamp, gpu_free: &mut Timestamp, cpu_time, gpu_time| {
+            let (frame, target) = context.create_frame(now);
+            let cpu_end = target + cpu_time;
+            let gpu_begin = cmp::max(cpu_end, *gpu_free);
+            *gpu_free = gpu_begin + gpu_time;
+            frame.mark(CPU, MarkType::Begin, target);
+            frame.mark(CPU, MarkType::End, cpu_end);
+            frame.mark(GPU, MarkType::Begin, gpu_begin);
+            frame.mark(GPU, MarkType::End, *gpu_free);
+            (target, cpu_end)
+        };
+
+        let mut now: Timestamp = 1_000_000_000_000;
+        let mut gpu_free = now;
+        let mut delay = 0;
+        for _ in 0..100 {
+            let (target, cpu_end) = render(now, &mut gpu_free, cpu_time, gpu_time);
+            delay = target - now;
+            now = cpu_end;
+        }
+        // GPU bound, so frames are held back by the difference.
+        assert!(delay > (gpu_time - cpu_time) / 2, "delay {delay}");
+        let estimate = frame_time();
+        assert_eq!(estimate, Some(gpu_time));
+
+        context.pause();
+        for _ in 0..20 {
+            now += 100_000_000;
+            let (target, cpu_end) = render(now, &mut gpu_free, 5 * cpu_time, 5 * gpu_time);
+            assert_eq!(target, now);
+            now = cpu_end;
+        }
+        assert_eq!(frame_time(), estimate);
+
+        context.resume();
+        now += 1_000_000_000;
+        for _ in 0..10 {
+            let (target, cpu_end) = render(now, &mut gpu_free, cpu_time, gpu_time);
+            assert_eq!(frame_time(), estimate);
+            delay = target - now;
+            now = cpu_end;
+        }
+        assert!(delay > (gpu_time - cpu_time) / 2, "delay {delay}");
+    }
+
+    #[test]
+    fn test_leaked_frame_is_reaped() {
+        let context = Arc::new(Context {
+            inner: Mutex::new(ContextInner::new(Profiler::with_writer(io::sink()))),
+        });
+        let timeout = context.inner.lock().config.frame_timeout;
+        let mut now: Timestamp = 1_000_000_000_000;
+        let (leaked, _) = context.create_frame(now);
+
+        // Keep frames flowing so that the timeout is not mistaken for a pause.
+        while now < 1_000_000_000_000 + 2 * timeout {
+            let (frame, target) = context.create_frame(now);
+            frame.mark(CPU, MarkType::Begin, target);
+            frame.mark(CPU, MarkType::End, target + 10_000_000);
+            now = target + 10_000_000;
+        }
+
+        assert_eq!(context.leaked_frames(), 1);
+        assert!(context.inner.lock().reference_frame.is_some());
+        leaked.mark(CPU, MarkType::Begin, now);
+    }
+
+    #[test]
+    fn test_degraded_context_fails_open() {
+        let context = Arc::new(Context {
+            inner: Mutex::new(ContextInner::new(Profiler::with_writer(io::sink()))),
+        });
+        let now: Timestamp = 1_000_000_000_000;
+        let (frame, _) = context.create_frame(now);
+        frame.fail(&Error::EmptyFrame);
+        assert!(context.is_degraded());
+
+        // Marks on frames created before and after the error are ignored.
+        frame.mark(CPU, MarkType::Begin, now);
+        let (frame, target) = context.create_frame(now + 1_000_000_000);
+        assert_eq!(target, now + 1_000_000_000);
+        frame.mark(CPU, MarkType::Begin, now);
+        assert!(context.inner.lock().frames.is_empty());
+        assert_eq!(
+            crate::error::report(frame.try_mark(CPU, MarkType::End, now)),
+            crate::error::ResultCode::Degraded
+        );
+    }
+
+    #[test]
+    fn test_profiler_ring_is_bounded() {
+        let context = Arc::new(Context {
+            inner: Mutex::new(ContextInner::new(Profiler::disabled())),
+        });
+        assert!(context.enable_profiler_ring(usize::MAX).is_err());
+        assert!(context.inner.lock().profiler_ring.is_none());
+
+        context.enable_profiler_ring(MAX_RING_CAPACITY).unwrap();
+        assert!(context.inner.lock().profiler_ring.is_some());
+    }
+
+    #[test]
+    fn test_invalid_marks_are_repaired() {
+        let context = Arc::new(Context {
+            inner: Mutex::new(ContextInner::new(Profiler::with_writer(io::sink()))),
+        });
+        let now: Timestamp = 1_000_000_000_000;
+        let ms = 1_000_000;
+        let marks = |frame: &Frame| context.inner.lock().frames[&frame.id].marks.clone();
+        // Far-future marks are rejected even without a frame timeout.
+        context
+            .set_config(ContextConfig {
+                frame_timeout: 0,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let (first, _) = context.create_frame(now);
+        first.mark(CPU, MarkType::Begin, now);
+        first.mark(CPU, MarkType::End, now + ms);
+
+        let (second, _) = context.create_frame(now + ms);
+        second.mark(CPU, MarkType::End, now + 3 * ms);
+        second.mark(CPU, MarkType::Begin, now + 4 * ms);
+        assert!(second.try_mark(CPU, MarkType::Begin, now).is_err());
+        second.mark(GPU, MarkType::Begin, now + 2 * ms);
+        assert!(second
+            .try_mark(GPU, MarkType::End, now + 100_000 * ms)
+            .is_err());
+        assert_eq!(
+            marks(&second),
+            BTreeMap::from([
+                ((CPU, MarkType::Begin), now + 3 * ms),
+                ((CPU, MarkType::End), now + 3 * ms),
+                ((GPU, MarkType::Begin), now + 3 * ms),
+            ])
+        );
+
+        let (third, _) = context.create_frame(now + 2 * ms);
+        third.mark(CPU, MarkType::Begin, now + ms);
+        assert_eq!(marks(&third)[&(CPU, MarkType::Begin)], now + 3 * ms);
+
+        // Cancelled frames do not hold back the marks of later frames.
+        let (cancelled, _) = context.create_frame(now + 3 * ms);
+        cancelled.mark(CPU, MarkType::Begin, now + 50 * ms);
+        cancelled.cancel();
+        let (fourth, _) = context.create_frame(now + 4 * ms);
+        fourth.mark(CPU, MarkType::Begin, now + 5 * ms);
+        assert_eq!(marks(&fourth)[&(CPU, MarkType::Begin)], now + 5 * ms);
+
+        assert_eq!(
+            context.mark_diagnostics(),
+            MarkDiagnostics {
+                future: 1,
+                duplicate: 1,
+                inverted: 1,
+                non_monotonic: 1,
+                out_of_order: 1,
+            }
+        );
+    }
+}
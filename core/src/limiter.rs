@@ -0,0 +1,28 @@
+use crate::config::ContextConfig;
+use crate::{Interval, Timestamp};
+
+/// Caps the frame rate by spacing frame starts at least a minimum frame interval apart.
+///
+/// Slots are counted from the start of the previous frame rather than on a fixed grid, so a
+/// missed slot does not let the following frames start early to catch up.
+#[derive(Default)]
+pub struct FrameLimiter {
+    last_start: Option<Timestamp>,
+}
+
+/// Minimum frame interval implied by `frame_rate_limit`.
+pub fn frame_rate_interval(config: &ContextConfig) -> Option<Interval> {
+    (config.frame_rate_limit > 0.).then(|| (1e9 / config.frame_rate_limit) as Interval)
+}
+
+impl FrameLimiter {
+    /// Earliest start of the next frame, or `None` if the frame rate is not capped.
+    pub fn next_slot(&self, min_interval: Option<Interval>) -> Option<Timestamp> {
+        Some(self.last_start?.saturating_add(min_interval?))
+    }
+
+    /// Records the start of a frame.
+    pub fn reserve(&mut self, start: Timestamp) {
+        self.last_start = Some(start);
+    }
+}
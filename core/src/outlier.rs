@@ -0,0 +1,61 @@
+use crate::config::ContextConfig;
+use crate::estimator::Estimator;
+
+/// Number of samples a section needs before its distribution is trusted to classify spikes.
+const WARMUP_SAMPLES: u32 = 8;
+
+/// A sample classified as a one-off spike.
+pub struct Outlier {
+    pub estimate: f64,
+    /// Down-weighted sample to feed into the estimator instead, if it should not be excluded.
+    pub replacement: Option<f64>,
+}
+
+/// Detects one-off spikes, such as shader compilation hitches, in a section's durations.
+///
+/// A sample is a spike if it exceeds the estimate by more than `outlier_sigma` standard
+/// deviations (and at least `outlier_min_deviation`). Spikes that persist for
+/// `outlier_persistence` consecutive frames are a real load change and are accepted again.
+#[derive(Default)]
+pub struct OutlierFilter {
+    samples: u32,
+    run: u32,
+}
+
+impl OutlierFilter {
+    pub fn classify(
+        &mut self,
+        config: &ContextConfig,
+        estimator: &dyn Estimator,
+        v: f64,
+    ) -> Option<Outlier> {
+        self.samples = self.samples.saturating_add(1);
+        if config.outlier_sigma == 0. || self.samples <= WARMUP_SAMPLES {
+            return None;
+        }
+
+        let estimate = estimator.get();
+        let threshold = (config.outlier_sigma * estimator.variance().sqrt())
+            .max(config.outlier_min_deviation as f64);
+        if v - estimate <= threshold {
+            self.run = 0;
+            return None;
+        }
+
+        // Keep accepting for the rest of the run once it has been recognized as a load change.
+        self.run = self.run.saturating_add(1);
+        if self.run >= config.outlier_persistence {
+            return None;
+        }
+
+        let replacement = if config.outlier_weight > 0. {
+            Some(estimate + config.outlier_weight * (v - estimate))
+        } else {
+            None
+        };
+        Some(Outlier {
+            estimate,
+            replacement,
+        })
+    }
+}
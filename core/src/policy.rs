@@ -0,0 +1,494 @@
+use std::cmp;
+use std::collections::BTreeMap;
+
+use crate::config::{ContextConfig, ErrorController, PacingMode};
+use crate::controller::PidController;
+use crate::estimator::{Estimator, EstimatorKind};
+use crate::ewma::EwmaEstimator;
+use crate::outlier::OutlierFilter;
+use crate::profiler::{ProfilerEvent, Recorder};
+use crate::{FrameId, Interval, SectionId, Timestamp};
+
+/// Measurements of a frame that has been retired by the context.
+pub struct FrameObservation<'a> {
+    pub frame_id: FrameId,
+    /// Queueing delay relative to the previously retired frame, if there is one.
+    pub queueing_delay: Option<Interval>,
+    /// Time between the beginning of the previously retired frame and this one.
+    pub frame_interval: Option<Interval>,
+    /// Duration of each section, with application overrides already applied.
+    pub inverse_throughput: &'a BTreeMap<SectionId, Interval>,
+    /// Timestamp of the last mark of the frame, if there is one.
+    pub end_ts: Option<Timestamp>,
+}
+
+/// State of the context at the time a new frame is requested.
+pub struct PacingInput<'a> {
+    pub now: Timestamp,
+    /// Target of the most recent frame, in flight or retired.
+    pub last_predicted_begin: Option<Timestamp>,
+    /// `predicted_error_delta` of the frames still in flight, oldest first.
+    pub in_flight_error_deltas: &'a [i64],
+    /// Earliest start allowed by the frame rate cap. The context delays the target to this slot
+    /// after planning.
+    pub frame_rate_slot: Option<Timestamp>,
+}
+
+pub struct PacingDecision {
+    pub target: Timestamp,
+    /// Predicted change of the queueing delay caused by this frame. Fed back through
+    /// [`PacingInput::in_flight_error_deltas`] until the frame is retired.
+    pub predicted_error_delta: i64,
+    /// Queueing delay the policy aimed to keep for this frame.
+    pub margin: Interval,
+}
+
+/// A control law that turns frame observations into frame start targets.
+pub trait PacingPolicy: Send {
+    /// Called when the config changes, and once when the policy is installed.
+    fn configure(&mut self, _config: &ContextConfig) {}
+
+    fn observe(
+        &mut self,
+        config: &ContextConfig,
+        observation: &FrameObservation,
+        recorder: &mut Recorder,
+    );
+
+    fn plan(&mut self, config: &ContextConfig, input: &PacingInput) -> PacingDecision;
+
+    /// Called after a discontinuity such as a pause. State about the queue should be dropped,
+    /// while estimates of the pipeline's throughput can be kept.
+    fn reset(&mut self) {}
+
+    /// Predicted frame time of the pipeline, if known.
+    fn frame_time(&self) -> Option<Interval> {
+        None
+    }
+
+    /// Selects the estimator used for a section. Policies that don't estimate per-section
+    /// throughput can ignore this.
+    fn set_section_estimator(
+        &mut self,
+        _config: &ContextConfig,
+        _section_id: SectionId,
+        _kind: EstimatorKind,
+    ) {
+    }
+}
+
+/// Paces frames such that the queueing delay converges to a safety margin, which is either `bias`
+/// or a multiple of the observed jitter.
+#[derive(Default)]
+pub struct DefaultPolicy {
+    reference_delay: Option<i64>,
+    delay_estimator: Option<EwmaEstimator>,
+    bandwidth_estimator: BTreeMap<SectionId, Box<dyn Estimator>>,
+    section_estimators: BTreeMap<SectionId, EstimatorKind>,
+    outlier_filters: BTreeMap<SectionId, OutlierFilter>,
+    pid: PidController,
+}
+
+/// Smallest bound of the queueing delay error, so that the correction never vanishes entirely.
+const MIN_ERROR_CLAMP: Interval = 100_000;
+
+impl DefaultPolicy {
+    /// Frame time of the slowest section.
+    fn predicted_duration(&self) -> Interval {
+        self.bandwidth_estimator
+            .values()
+            .map(|e| e.get() as Interval)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Bound of the queueing delay error. Scales with the frame time, so that slow pipelines
+    /// can correct a queue of a whole frame while fast ones don't overshoot by several frames.
+    fn max_error(&self, config: &ContextConfig) -> Interval {
+        let predicted_duration = self.predicted_duration();
+        if config.error_clamp_ratio == 0. || predicted_duration == 0 {
+            config.max_error
+        } else {
+            cmp::max(
+                (config.error_clamp_ratio * predicted_duration as f64) as Interval,
+                MIN_ERROR_CLAMP,
+            )
+        }
+    }
+
+    /// Upper bound of a sample of an estimate. Grows with the estimate so that slow
+    /// pipelines are not capped at `floor`.
+    fn sample_cap(config: &ContextConfig, floor: Interval, estimate: f64) -> Interval {
+        cmp::max(
+            floor,
+            (config.frame_time_clamp_ratio * estimate) as Interval,
+        )
+    }
+
+    /// Combines the jitter of the queueing delay with that of the slowest section, which both
+    /// eat into the margin before the pipeline runs dry. Falls back to `bias` until a queueing
+    /// delay has been observed.
+    fn margin(&self, config: &ContextConfig) -> Interval {
+        let Some(delay_estimator) = self
+            .delay_estimator
+            .as_ref()
+            .filter(|_| config.margin_sigma != 0.)
+        else {
+            return config.bias;
+        };
+        let delay_variance = delay_estimator.variance();
+        let frame_time_variance = self
+            .bandwidth_estimator
+            .values()
+            .max_by(|a, b| a.get().total_cmp(&b.get()))
+            .map_or(0., |e| e.variance());
+        let sigma = (delay_variance + frame_time_variance).sqrt();
+        let max_latency =
+            Self::sample_cap(config, config.max_latency, self.predicted_duration() as f64);
+        ((config.margin_sigma * sigma) as Interval)
+            .min(max_latency)
+            .max(config.min_margin)
+    }
+}
+
+impl PacingPolicy for DefaultPolicy {
+    fn configure(&mut self, config: &ContextConfig) {
+        for estimator in self.bandwidth_estimator.values_mut() {
+            estimator.configure(config);
+        }
+        if let Some(estimator) = &mut self.delay_estimator {
+            estimator.configure(config);
+        }
+    }
+
+    fn observe(
+        &mut self,
+        config: &ContextConfig,
+        observation: &FrameObservation,
+        recorder: &mut Recorder,
+    ) {
+        if let Some(queueing_delay) = observation.queueing_delay {
+            let max_latency =
+                Self::sample_cap(config, config.max_latency, self.predicted_duration() as f64);
+            let queueing_delay = cmp::min(queueing_delay, max_latency);
+            self.reference_delay = Some(queueing_delay as i64);
+            self.delay_estimator
+                .get_or_insert_with(|| {
+                    EwmaEstimator::with_time_constant(config.beta, config.estimator_time_constant)
+                })
+                .update(queueing_delay as f64, observation.frame_interval);
+        }
+
+        for (&section_id, &duration) in observation.inverse_throughput {
+            let kind = self.section_estimators.get(&section_id).copied();
+            let estimator = self
+                .bandwidth_estimator
+                .entry(section_id)
+                .or_insert_with(|| kind.unwrap_or_default().create(config));
+            let duration = cmp::min(
+                duration,
+                Self::sample_cap(config, config.max_frame_time, estimator.get()),
+            );
+            let outlier = self
+                .outlier_filters
+                .entry(section_id)
+                .or_default()
+                .classify(config, estimator.as_ref(), duration as f64);
+
+            match outlier {
+                None => {
+                    estimator.update(duration as f64, observation.frame_interval);
+                    if let Some(end_ts) = observation.end_ts {
+                        recorder.record(ProfilerEvent::Estimate {
+                            frame_id: observation.frame_id,
+                            section_id,
+                            duration,
+                            estimate: estimator.get() as Interval,
+                            timestamp: end_ts,
+                        });
+                    }
+                }
+                Some(outlier) => {
+                    if let Some(end_ts) = observation.end_ts {
+                        recorder.record(ProfilerEvent::Outlier {
+                            frame_id: observation.frame_id,
+                            section_id,
+                            duration,
+                            estimate: outlier.estimate as Interval,
+                            timestamp: end_ts,
+                        });
+                    }
+                    if let Some(replacement) = outlier.replacement {
+                        estimator.update(replacement, observation.frame_interval);
+                    }
+                }
+            }
+        }
+    }
+
+    fn plan(&mut self, config: &ContextConfig, input: &PacingInput) -> PacingDecision {
+        let bias = match config.mode {
+            PacingMode::MinimumLatency => self.margin(config),
+            PacingMode::Balanced | PacingMode::Throughput => {
+                cmp::max(self.margin(config), config.latency_budget)
+            }
+        } as i64;
+        let error = if let Some(actual) = self.reference_delay {
+            input
+                .in_flight_error_deltas
+                .iter()
+                .fold(actual, |acc, delta| (acc + delta).max(0))
+                - bias
+        } else {
+            0
+        };
+        // In throughput mode, frames are only held back while the queue exceeds the budget.
+        let free_running = config.mode == PacingMode::Throughput && error <= 0;
+
+        let max_error = self.max_error(config);
+        let clamped_error = error.clamp(-(max_error as i64), max_error as i64);
+
+        let now = input.now;
+        let predicted_duration = self.predicted_duration();
+        // While the frame rate cap holds frames back, the queue runs dry on purpose. Correcting
+        // for that would only wind up the controller against the cap.
+        let capped = error <= 0
+            && input.frame_rate_slot.is_some_and(|slot| {
+                input
+                    .last_predicted_begin
+                    .map_or(now, |top| top.saturating_add(predicted_duration))
+                    < slot
+            });
+        let correction = match config.error_controller {
+            _ if free_running || capped => 0.,
+            ErrorController::Proportional => config.alpha * clamped_error as f64,
+            ErrorController::Pid => self.pid.update(config, clamped_error as f64, max_error),
+        };
+        let mut predicted_error_delta = -correction as i64;
+        // A correction larger than the frame time means starting right away, not in the past.
+        let target_frame_time = cmp::max(predicted_duration as i64 - predicted_error_delta, 0);
+        predicted_error_delta = predicted_duration as i64 - target_frame_time;
+
+        let mut target;
+        if let Some(last_frame_top) = input.last_predicted_begin {
+            target = last_frame_top.saturating_add(target_frame_time as Interval);
+            if free_running || target <= now {
+                // Starting now instead grows or shrinks the queue by the difference.
+                predicted_error_delta += target as i64 - now as i64;
+                target = now;
+            }
+        } else {
+            target = now;
+        }
+
+        PacingDecision {
+            target,
+            predicted_error_delta,
+            margin: bias as Interval,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.reference_delay = None;
+        self.pid = PidController::default();
+    }
+
+    fn frame_time(&self) -> Option<Interval> {
+        Some(self.predicted_duration()).filter(|&duration| duration != 0)
+    }
+
+    fn set_section_estimator(
+        &mut self,
+        config: &ContextConfig,
+        section_id: SectionId,
+        kind: EstimatorKind,
+    ) {
+        if self.section_estimators.insert(section_id, kind) != Some(kind) {
+            self.bandwidth_estimator
+                .insert(section_id, kind.create(config));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::{Profiler, RingSink};
+    use crate::FrameId;
+
+    const FRAME_TIME: Interval = 10_000_000;
+
+    /// Retires a frame whose only section took `duration`.
+    fn observe(
+        policy: &mut DefaultPolicy,
+        config: &ContextConfig,
+        profiler: &mut Profiler,
+        frame: u64,
+        duration: Interval,
+    ) {
+        let inverse_throughput = BTreeMap::from([(0, duration)]);
+        let observation = FrameObservation {
+            frame_id: FrameId(frame),
+            queueing_delay: None,
+            frame_interval: Some(duration),
+            inverse_throughput: &inverse_throughput,
+            end_ts: Some(frame * FRAME_TIME),
+        };
+        policy.observe(config, &observation, &mut profiler.recorder());
+    }
+
+    #[test]
+    fn test_margin_falls_back_to_bias() {
+        let config = ContextConfig::default();
+        assert_eq!(DefaultPolicy::default().margin(&config), config.bias);
+    }
+
+    #[test]
+    fn test_spike_is_excluded() {
+        let config = ContextConfig::default();
+        let mut policy = DefaultPolicy::default();
+        let (ring, reader) = RingSink::new(1024);
+        let mut profiler = Profiler::disabled();
+        profiler.add_sink(Box::new(ring));
+
+        let mut frame = 0;
+        let mut run = |policy: &mut DefaultPolicy, duration, frames| {
+            for _ in 0..frames {
+                frame += 1;
+                observe(policy, &config, &mut profiler, frame, duration);
+            }
+        };
+        run(&mut policy, FRAME_TIME, 20);
+        let estimate = policy.frame_time().unwrap();
+        run(&mut policy, 3 * FRAME_TIME, 1);
+        assert_eq!(policy.frame_time(), Some(estimate));
+        run(&mut policy, FRAME_TIME, 5);
+
+        // A sustained shift is rejected until it has lasted `outlier_persistence` frames.
+        let persistence = config.outlier_persistence;
+        run(&mut policy, 2 * FRAME_TIME, persistence - 1);
+        assert_eq!(policy.frame_time(), Some(estimate));
+        run(&mut policy, 2 * FRAME_TIME, 1);
+        assert!(policy.frame_time().unwrap() > estimate);
+        run(&mut policy, 2 * FRAME_TIME, 20);
+        assert!(policy.frame_time().unwrap() > 2 * FRAME_TIME * 99 / 100);
+        drop(profiler);
+
+        let outliers: Vec<_> = reader
+            .take(usize::MAX)
+            .into_iter()
+            .filter_map(|event| match event {
+                ProfilerEvent::Outlier {
+                    duration, estimate, ..
+                } => Some((duration, estimate)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(outliers.len(), persistence as usize);
+        assert_eq!(outliers[0], (3 * FRAME_TIME, estimate));
+    }
+
+    /// Frame time estimates of a policy using `kind` for its only section.
+    fn estimates(kind: EstimatorKind, durations: &[Interval]) -> Vec<Interval> {
+        let config = ContextConfig {
+            outlier_sigma: 0.,
+            ..Default::default()
+        };
+        let mut policy = DefaultPolicy::default();
+        policy.set_section_estimator(&config, 0, kind);
+        let mut profiler = Profiler::disabled();
+        (0..)
+            .zip(durations)
+            .map(|(frame, &duration)| {
+                observe(&mut policy, &config, &mut profiler, frame, duration);
+                policy.frame_time().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_kalman_against_ewma() {
+        let jitter = FRAME_TIME / 10;
+        let mut durations = vec![FRAME_TIME; 30];
+        durations.extend((0..60).map(|i| FRAME_TIME + jitter - i % 2 * 2 * jitter));
+        durations.extend([3 * FRAME_TIME / 2; 20]);
+        let ewma = estimates(EstimatorKind::Ewma, &durations);
+        let kalman = estimates(EstimatorKind::Kalman, &durations);
+
+        // Under alternating jitter, the Kalman filter barely moves while EWMA follows it.
+        let swing = |estimates: &[Interval]| {
+            estimates[60..90].iter().max().unwrap() - estimates[60..90].iter().min().unwrap()
+        };
+        assert!(swing(&kalman) * 4 < swing(&ewma), "{}", swing(&kalman));
+        // Yet it picks up the step sooner.
+        let settle = |estimates: &[Interval]| {
+            estimates[90..]
+                .iter()
+                .position(|&estimate| estimate.abs_diff(3 * FRAME_TIME / 2) < FRAME_TIME / 64)
+                .unwrap()
+        };
+        assert!(settle(&kalman) < settle(&ewma));
+    }
+
+    /// Starts frames at a fixed interval.
+    struct FixedInterval {
+        interval: Interval,
+    }
+
+    impl PacingPolicy for FixedInterval {
+        fn observe(
+            &mut self,
+            _config: &ContextConfig,
+            _observation: &FrameObservation,
+            _recorder: &mut Recorder,
+        ) {
+        }
+
+        fn plan(&mut self, _config: &ContextConfig, input: &PacingInput) -> PacingDecision {
+            let target = input
+                .last_predicted_begin
+                .map_or(input.now, |last| cmp::max(input.now, last + self.interval));
+            PacingDecision {
+                target,
+                predicted_error_delta: 0,
+                margin: 0,
+            }
+        }
+    }
+
+    #[test]
+    fn test_policy_is_replaceable() {
+        let config = ContextConfig::default();
+        let mut policy: Box<dyn PacingPolicy> = Box::<DefaultPolicy>::default();
+        let mut now: Timestamp = 1_000_000_000_000;
+        let mut last_predicted_begin = None;
+        let mut targets = Vec::new();
+        for frame in 0..20 {
+            if frame == 10 {
+                policy = Box::new(FixedInterval {
+                    interval: 10_000_000,
+                });
+                policy.configure(&config);
+            }
+            let input = PacingInput {
+                now,
+                last_predicted_begin,
+                in_flight_error_deltas: &[],
+                frame_rate_slot: None,
+            };
+            let target = policy.plan(&config, &input).target;
+            targets.push(target);
+            last_predicted_begin = Some(target);
+            now = target + 1_000_000;
+        }
+
+        // Without observations, the default policy starts frames right away. The replacement
+        // takes over from the next frame.
+        assert!(targets[..10]
+            .windows(2)
+            .all(|pair| pair[1] - pair[0] == 1_000_000));
+        assert!(targets[9..]
+            .windows(2)
+            .all(|pair| pair[1] - pair[0] == 10_000_000));
+    }
+}
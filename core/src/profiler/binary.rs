@@ -0,0 +1,271 @@
+use std::io::{self, BufRead, BufWriter, Read, Write};
+
+use super::files::{ProfilerConfig, TraceFiles};
+use super::record::ProfilerRecord;
+use super::{ProfilerEvent, ProfilerSink};
+
+const MAGIC: [u8; 8] = *b"LFX2TRC\0";
+/// Incremented when records change incompatibly. Fields and kinds may be added without a bump,
+/// as readers skip what they do not understand.
+const VERSION: u64 = 1;
+/// Records longer than this are treated as corruption.
+const MAX_RECORD_SIZE: u64 = 1024;
+
+pub(super) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub(super) fn read_varint(input: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        input.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint is too long",
+    ))
+}
+
+/// Writes events as a compact binary trace, which can be converted to a Chrome trace with
+/// `lfx2-convert`.
+///
+/// A trace starts with a magic number and a varint version. Each event follows as a varint
+/// length and a [`ProfilerRecord`] encoded as varints. A trace that is cut short, for example by
+/// a crash, can still be read up to its last complete record.
+pub struct BinarySink {
+    /// `None` if writing failed.
+    output: Option<BufWriter<Box<dyn Write + Send>>>,
+    files: Option<TraceFiles>,
+    /// Bytes written to the current trace.
+    written: u64,
+    /// Scratch buffers for encoding records.
+    buf: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+impl BinarySink {
+    pub fn new(writer: impl Write + Send + 'static) -> BinarySink {
+        let mut sink = BinarySink {
+            output: None,
+            files: None,
+            written: 0,
+            buf: Vec::new(),
+            payload: Vec::new(),
+        };
+        sink.start(writer);
+        sink
+    }
+
+    /// Writes rotating traces to the configured directory, or returns `None` after printing a
+    /// warning.
+    pub fn open(config: &ProfilerConfig) -> Option<BinarySink> {
+        let (files, file) = TraceFiles::open(config, "bin")?;
+        let mut sink = BinarySink::new(file);
+        sink.files = Some(files);
+        Some(sink)
+    }
+
+    /// Starts a new trace.
+    fn start(&mut self, writer: impl Write + Send + 'static) {
+        let mut output = BufWriter::new(Box::new(writer) as Box<dyn Write + Send>);
+        self.buf.clear();
+        self.buf.extend_from_slice(&MAGIC);
+        write_varint(&mut self.buf, VERSION);
+        let _ = output.write_all(&self.buf);
+        self.output = Some(output);
+        self.written = self.buf.len() as u64;
+    }
+
+    fn rotate(&mut self) {
+        let Some(files) = &mut self.files else {
+            return;
+        };
+        match files.next() {
+            Ok(file) => {
+                self.finish();
+                self.start(file);
+            }
+            Err(e) => {
+                eprintln!("LFX2 WARN: Failed to rotate trace: {e}. Profiling is disabled.");
+                self.finish();
+                self.files = None;
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(output) = &mut self.output {
+            let _ = output.flush();
+        }
+        self.output = None;
+    }
+}
+
+impl Drop for BinarySink {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl ProfilerSink for BinarySink {
+    fn record(&mut self, event: &ProfilerEvent) {
+        let Some(output) = &mut self.output else {
+            return;
+        };
+        self.payload.clear();
+        ProfilerRecord::from(event).encode(&mut self.payload);
+        self.buf.clear();
+        write_varint(&mut self.buf, self.payload.len() as u64);
+        self.buf.extend_from_slice(&self.payload);
+        let _ = output.write_all(&self.buf);
+        self.written += self.buf.len() as u64;
+        if self
+            .files
+            .as_ref()
+            .is_some_and(|files| files.due(self.written))
+        {
+            self.rotate();
+        }
+    }
+}
+
+/// Reads the events of a binary trace written by [`BinarySink`].
+pub struct BinaryReader<R> {
+    input: R,
+    /// Records that were skipped because they were written by a newer version.
+    skipped: u64,
+    incomplete: bool,
+}
+
+impl<R: BufRead> BinaryReader<R> {
+    /// Checks the header of the trace.
+    pub fn new(mut input: R) -> io::Result<BinaryReader<R>> {
+        let mut magic = [0; MAGIC.len()];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an LFX2 binary trace",
+            ));
+        }
+        let version = read_varint(&mut input)?;
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported trace version {version}, expected {VERSION}"),
+            ));
+        }
+        Ok(BinaryReader {
+            input,
+            skipped: 0,
+            incomplete: false,
+        })
+    }
+
+    /// Number of records that were not understood and skipped.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Whether the trace ended in the middle of a record, or could not be read further.
+    pub fn incomplete(&self) -> bool {
+        self.incomplete
+    }
+
+    fn read_record(&mut self) -> io::Result<Vec<u8>> {
+        let length = read_varint(&mut self.input)?;
+        if length > MAX_RECORD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record is too long",
+            ));
+        }
+        let mut record = Vec::new();
+        (&mut self.input).take(length).read_to_end(&mut record)?;
+        if record.len() as u64 != length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(record)
+    }
+}
+
+impl<R: BufRead> Iterator for BinaryReader<R> {
+    type Item = ProfilerEvent;
+
+    fn next(&mut self) -> Option<ProfilerEvent> {
+        while !self.incomplete {
+            match self.input.fill_buf() {
+                Ok([]) => return None,
+                Ok(_) => {}
+                Err(_) => break,
+            }
+            let Ok(record) = self.read_record() else {
+                break;
+            };
+            match ProfilerRecord::decode(&record).and_then(|record| record.event()) {
+                Some(event) => return Some(event),
+                None => self.skipped += 1,
+            }
+        }
+        self.incomplete = true;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PacingMode;
+    use crate::profiler::tests::Shared;
+    use crate::{FrameId, MarkType};
+
+    #[test]
+    fn test_truncated_trace_is_readable() {
+        let events = [
+            ProfilerEvent::Mark {
+                frame_id: FrameId(1),
+                section_id: 0,
+                mark_type: MarkType::Begin,
+                timestamp: 1_000_000_000_000,
+            },
+            ProfilerEvent::Mode {
+                mode: PacingMode::Balanced,
+                latency_budget: 2_000_000,
+                timestamp: 1_000_000_500_000,
+            },
+            ProfilerEvent::Estimate {
+                frame_id: FrameId(1),
+                section_id: 3,
+                duration: 5_000_000,
+                estimate: 4_800_000,
+                timestamp: 1_000_001_000_000,
+            },
+        ];
+        let buffer = Shared::default();
+        let mut sink = BinarySink::new(buffer.clone());
+        for event in &events {
+            sink.record(event);
+        }
+        drop(sink);
+        let trace = buffer.0.lock().clone();
+
+        let mut reader = BinaryReader::new(&trace[..]).unwrap();
+        assert_eq!(reader.by_ref().collect::<Vec<_>>(), events);
+        assert!(!reader.incomplete());
+
+        for length in MAGIC.len() + 1..trace.len() {
+            let read: Vec<_> = BinaryReader::new(&trace[..length]).unwrap().collect();
+            assert!(read.len() < events.len());
+            assert_eq!(read[..], events[..read.len()]);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::Local;
+
+const MB: u64 = 1 << 20;
+
+/// Traces modified more recently than this may still be written by another process, and are
+/// only deleted to make room if this process wrote them.
+const LIVE_TRACE_AGE: Duration = Duration::from_secs(60);
+
+/// Encoding of the traces written to disk.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// Chrome trace JSON, which can be opened directly.
+    #[default]
+    Json,
+    /// Compact binary records, which must be converted with `lfx2-convert` before viewing.
+    Binary,
+    /// Perfetto protobuf, which can be opened in the Perfetto UI.
+    Perfetto,
+}
+
+/// Where traces are written, and how much disk space they may take.
+///
+/// [`ProfilerConfig::from_env`] reads the following variables:
+/// - `LFX2_PROFILE=1` enables profiling.
+/// - `LFX2_PROFILE_FORMAT` selects the [`TraceFormat`]: `json`, `binary` or `perfetto`.
+/// - `LFX2_PROFILE_DIR` overrides the output directory.
+/// - `LFX2_PROFILE_ROTATE_MB` and `LFX2_PROFILE_ROTATE_MINUTES` start a new trace once the
+///   current one reaches a size or an age. Zero disables the limit.
+/// - `LFX2_PROFILE_QUOTA_MB` caps the total size of the traces in the output directory. Zero
+///   disables the quota.
+#[derive(Clone, Debug)]
+pub struct ProfilerConfig {
+    pub enabled: bool,
+    pub format: TraceFormat,
+    /// Defaults to `$XDG_STATE_HOME/lfx2`, `~/.local/state/lfx2` or `%LOCALAPPDATA%\lfx2`.
+    pub directory: Option<PathBuf>,
+    /// Size after which a new trace is started, in bytes.
+    pub rotate_size: u64,
+    /// Age after which a new trace is started.
+    pub rotate_interval: Option<Duration>,
+    /// Total size of the traces in `directory`, in bytes. The oldest traces are deleted first,
+    /// except those another process may still be writing to.
+    pub quota: u64,
+}
+
+impl Default for ProfilerConfig {
+    fn default() -> Self {
+        ProfilerConfig {
+            enabled: false,
+            format: TraceFormat::Json,
+            directory: default_directory(),
+            rotate_size: 256 * MB,
+            rotate_interval: None,
+            quota: 2048 * MB,
+        }
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn default_directory() -> Option<PathBuf> {
+    let base = if cfg!(target_os = "windows") {
+        PathBuf::from(env_var("LOCALAPPDATA")?)
+    } else {
+        match env_var("XDG_STATE_HOME") {
+            Some(state_home) => PathBuf::from(state_home),
+            None => Path::new(&env_var("HOME")?).join(".local/state"),
+        }
+    };
+    Some(base.join("lfx2"))
+}
+
+impl ProfilerConfig {
+    pub fn from_env() -> ProfilerConfig {
+        let number = |name: &str| env_var(name)?.parse::<u64>().ok();
+        let default = ProfilerConfig::default();
+        ProfilerConfig {
+            enabled: env_var("LFX2_PROFILE").as_deref() == Some("1"),
+            format: match env_var("LFX2_PROFILE_FORMAT").as_deref() {
+                Some("binary") => TraceFormat::Binary,
+                Some("perfetto") => TraceFormat::Perfetto,
+                _ => default.format,
+            },
+            directory: env_var("LFX2_PROFILE_DIR")
+                .map(PathBuf::from)
+                .or(default.directory),
+            rotate_size: number("LFX2_PROFILE_ROTATE_MB").map_or(default.rotate_size, |mb| mb * MB),
+            rotate_interval: number("LFX2_PROFILE_ROTATE_MINUTES")
+                .map_or(default.rotate_interval, |minutes| {
+                    (minutes > 0).then(|| Duration::from_secs(minutes * 60))
+                }),
+            quota: number("LFX2_PROFILE_QUOTA_MB").map_or(default.quota, |mb| mb * MB),
+        }
+    }
+}
+
+/// Rotating trace files in the output directory.
+pub struct TraceFiles {
+    config: ProfilerConfig,
+    directory: PathBuf,
+    extension: &'static str,
+    current: PathBuf,
+    /// Traces this writer has finished, which can be deleted regardless of their age.
+    closed: Vec<PathBuf>,
+    opened_at: Instant,
+}
+
+impl TraceFiles {
+    /// Creates the first trace, printing a warning on failure.
+    pub fn open(config: &ProfilerConfig, extension: &'static str) -> Option<(TraceFiles, File)> {
+        let mut files = TraceFiles {
+            config: config.clone(),
+            directory: config.directory.clone().unwrap_or_default(),
+            extension,
+            current: PathBuf::new(),
+            closed: Vec::new(),
+            opened_at: Instant::now(),
+        };
+        let result = if config.directory.is_none() {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no output directory is configured",
+            ))
+        } else {
+            fs::create_dir_all(&files.directory).and_then(|()| files.next())
+        };
+        match result {
+            Ok(file) => {
+                eprintln!("LFX2: Writing traces to {}", files.current.display());
+                Some((files, file))
+            }
+            Err(e) => {
+                eprintln!(
+                    "LFX2 WARN: Failed to create a trace in {}: {e}. Profiling is disabled.",
+                    files.directory.display()
+                );
+                None
+            }
+        }
+    }
+
+    /// Creates the next trace, then deletes old traces that exceed the quota.
+    pub fn next(&mut self) -> io::Result<File> {
+        let date = Local::now().format("%Y.%m.%d-%H.%M.%S");
+        let extension = self.extension;
+        let mut suffix = 0;
+        let file = loop {
+            let filename = match suffix {
+                0 => format!("lfx2.{date}.{extension}"),
+                _ => format!("lfx2.{date}.{suffix}.{extension}"),
+            };
+            let path = self.directory.join(filename);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    let previous = mem::replace(&mut self.current, path);
+                    if !previous.as_os_str().is_empty() {
+                        self.closed.push(previous);
+                    }
+                    break file;
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => suffix += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        self.opened_at = Instant::now();
+        self.enforce_quota();
+        Ok(file)
+    }
+
+    /// Whether the current trace should be rotated after `written` bytes.
+    pub fn due(&self, written: u64) -> bool {
+        let config = &self.config;
+        (config.rotate_size != 0 && written >= config.rotate_size)
+            || config
+                .rotate_interval
+                .is_some_and(|interval| self.opened_at.elapsed() >= interval)
+    }
+
+    /// Deletes the oldest traces until the others fit in the quota, reserving room for the
+    /// current trace to grow to its full size. Only traces in the same format are counted, and
+    /// those that may be written by another process are left alone.
+    fn enforce_quota(&mut self) {
+        if self.config.quota == 0 {
+            return;
+        }
+        let Ok(entries) = fs::read_dir(&self.directory) else {
+            return;
+        };
+        let suffix = format!(".{}", self.extension);
+        let traces = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let name = entry.file_name();
+                let name = name.to_str()?;
+                if !name.starts_with("lfx2.") || !name.ends_with(&suffix) {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                Some((entry.path(), modified, metadata.len()))
+            })
+            .filter(|(path, _, _)| *path != self.current)
+            .collect();
+        let budget = self.config.quota.saturating_sub(self.config.rotate_size);
+        let live_age = cmp::max(
+            LIVE_TRACE_AGE,
+            self.config.rotate_interval.unwrap_or_default(),
+        );
+        let live_since = SystemTime::now()
+            .checked_sub(live_age)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let live = |path: &Path, modified| {
+            modified >= live_since && !self.closed.iter().any(|closed| closed == path)
+        };
+        for path in expired_traces(traces, budget, live) {
+            self.closed.retain(|closed| *closed != path);
+            if let Err(e) = fs::remove_file(&path) {
+                eprintln!(
+                    "LFX2 WARN: Failed to delete old trace {}: {e}",
+                    path.display()
+                );
+            }
+        }
+    }
+}
+
+/// Oldest traces to delete so that the total size of the remaining ones is within `budget`.
+/// Traces that may still be written to count towards the total, but are never deleted.
+fn expired_traces(
+    mut traces: Vec<(PathBuf, SystemTime, u64)>,
+    budget: u64,
+    live: impl Fn(&Path, SystemTime) -> bool,
+) -> Vec<PathBuf> {
+    traces.sort_by_key(|&(_, modified, _)| modified);
+    let mut total: u64 = traces.iter().map(|&(_, _, size)| size).sum();
+    traces
+        .into_iter()
+        .filter(|(path, modified, _)| !live(path, *modified))
+        .take_while(|&(_, _, size)| {
+            let expired = total > budget;
+            total -= size;
+            expired
+        })
+        .map(|(path, _, _)| path)
+        .collect()
+}
+
+/// Counts the bytes written through it.
+pub struct Counter<'a, W> {
+    pub inner: &'a mut W,
+    pub written: u64,
+}
+
+impl<W: Write> Write for Counter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expired_traces() {
+        let trace = |name: &str, age: u64, size: u64| {
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1000 - age);
+            (PathBuf::from(name), modified, size)
+        };
+        let live_since = SystemTime::UNIX_EPOCH + Duration::from_secs(995);
+        let live = |_: &Path, modified| modified >= live_since;
+        let mut traces = vec![trace("b", 20, 40), trace("a", 30, 50), trace("c", 10, 30)];
+        assert_eq!(
+            expired_traces(traces.clone(), 120, live),
+            Vec::<PathBuf>::new()
+        );
+        assert_eq!(
+            expired_traces(traces.clone(), 70, live),
+            vec![PathBuf::from("a")]
+        );
+        assert_eq!(
+            expired_traces(traces.clone(), 29, live),
+            vec![PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")]
+        );
+
+        // Another process is still writing to "d", so older traces make room for it instead.
+        traces.push(trace("d", 1, 60));
+        assert_eq!(
+            expired_traces(traces.clone(), 120, live),
+            vec![PathBuf::from("a"), PathBuf::from("b")]
+        );
+        assert_eq!(
+            expired_traces(traces, 0, live),
+            vec![PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")]
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt;
+use std::io::{BufWriter, Write};
+
+use super::files::{Counter, ProfilerConfig, TraceFiles};
+use super::{ProfilerEvent, ProfilerSink};
+use crate::MarkType;
+
+/// Writes events as a Chrome trace, which can be opened in `chrome://tracing` or Perfetto.
+pub struct JsonSink {
+    /// `None` if writing failed.
+    output: Option<BufWriter<Box<dyn Write + Send>>>,
+    files: Option<TraceFiles>,
+    /// Bytes written to the current trace.
+    written: u64,
+    is_first_mark: bool,
+}
+
+impl JsonSink {
+    pub fn new(writer: impl Write + Send + 'static) -> JsonSink {
+        let mut sink = JsonSink {
+            output: None,
+            files: None,
+            written: 0,
+            is_first_mark: true,
+        };
+        sink.start(writer);
+        sink
+    }
+
+    /// Writes rotating traces to the configured directory, or returns `None` after printing a
+    /// warning.
+    pub fn open(config: &ProfilerConfig) -> Option<JsonSink> {
+        let (files, file) = TraceFiles::open(config, "json")?;
+        let mut sink = JsonSink::new(file);
+        sink.files = Some(files);
+        Some(sink)
+    }
+
+    /// Starts a new trace.
+    fn start(&mut self, writer: impl Write + Send + 'static) {
+        let mut output = BufWriter::new(Box::new(writer) as Box<dyn Write + Send>);
+        let _ = writeln!(output, "[");
+        self.output = Some(output);
+        self.written = 0;
+        self.is_first_mark = true;
+    }
+
+    fn event(&mut self, event: fmt::Arguments) {
+        let Some(output) = &mut self.output else {
+            return;
+        };
+        let comma = if self.is_first_mark { "" } else { ",\n" };
+        self.is_first_mark = false;
+        let mut counter = Counter {
+            inner: output,
+            written: 0,
+        };
+        let _ = write!(counter, "{comma}  {event}");
+        self.written += counter.written;
+        if self
+            .files
+            .as_ref()
+            .is_some_and(|files| files.due(self.written))
+        {
+            self.rotate();
+        }
+    }
+
+    fn rotate(&mut self) {
+        let Some(files) = &mut self.files else {
+            return;
+        };
+        match files.next() {
+            Ok(file) => {
+                self.finish();
+                self.start(file);
+            }
+            Err(e) => {
+                eprintln!("LFX2 WARN: Failed to rotate trace: {e}. Profiling is disabled.");
+                self.finish();
+                self.files = None;
+            }
+        }
+    }
+
+    /// Terminates the JSON array, so that the trace is complete.
+    fn finish(&mut self) {
+        if let Some(output) = &mut self.output {
+            let _ = writeln!(output, "\n]");
+            let _ = output.flush();
+        }
+        self.output = None;
+    }
+}
+
+impl Drop for JsonSink {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl ProfilerSink for JsonSink {
+    fn record(&mut self, event: &ProfilerEvent) {
+        match *event {
+            ProfilerEvent::Mark {
+                frame_id,
+                section_id,
+                mark_type,
+                timestamp,
+            } => {
+                let name = frame_id.0;
+                let tid = section_id;
+                let ph = match mark_type {
+                    MarkType::Begin => "B",
+                    MarkType::End => "E",
+                };
+                let ts = timestamp / 1000;
+                self.event(format_args!(
+                    r#"{{"name": "{name}", "cat": "MARKER", "ph": "{ph}", "pid": 1, "tid": {tid}, "ts": {ts}}}"#
+                ));
+            }
+            ProfilerEvent::Sleep {
+                start_time,
+                end_time,
+                ..
+            } => {
+                let name = "Sleep";
+                let tid = 9999;
+                let start = start_time / 1000;
+                let end = end_time / 1000;
+                self.event(format_args!(
+                    r#"{{"name": "{name}", "cat": "MARKER", "ph": "B", "pid": 1, "tid": {tid}, "ts": {start}}},
+              {{"name": "{name}", "cat": "MARKER", "ph": "E", "pid": 1, "tid": {tid}, "ts": {end}}}"#
+                ));
+            }
+            ProfilerEvent::Latency {
+                latency,
+                queueing_delay,
+                finish_time,
+                ..
+            } => {
+                let ts = finish_time / 1000;
+                self.event(format_args!(
+                    r#"{{"name": "Latency", "cat": "LATENCY", "ph": "C", "pid": 1, "tid": "10000", "ts": {ts}, "args": {{"latency": {latency}, "queueing_delay": {queueing_delay}}}}}"#
+                ));
+            }
+            ProfilerEvent::FrameTime {
+                top_interval,
+                bop_interval,
+                finish_time,
+                ..
+            } => {
+                let ts = finish_time / 1000;
+                self.event(format_args!(
+                    r#"{{"name": "Frame Time", "cat": "LATENCY", "ph": "C", "pid": 1, "tid": "10000", "ts": {ts}, "args": {{"top_interval": {top_interval}, "bop_interval": {bop_interval}}}}}"#
+                ));
+            }
+            ProfilerEvent::Margin { margin, timestamp } => {
+                let ts = timestamp / 1000;
+                self.event(format_args!(
+                    r#"{{"name": "Margin", "cat": "LATENCY", "ph": "C", "pid": 1, "tid": "10000", "ts": {ts}, "args": {{"margin": {margin}}}}}"#
+                ));
+            }
+            ProfilerEvent::Mode {
+                mode,
+                latency_budget,
+                timestamp,
+            } => {
+                let ts = timestamp / 1000;
+                self.event(format_args!(
+                    r#"{{"name": "Mode", "cat": "MODE", "ph": "i", "s": "g", "pid": 1, "tid": "10000", "ts": {ts}, "args": {{"mode": "{mode:?}", "latency_budget": {latency_budget}}}}}"#
+                ));
+            }
+            ProfilerEvent::Outlier {
+                frame_id,
+                section_id,
+                duration,
+                estimate,
+                timestamp,
+            } => {
+                let name = frame_id.0;
+                let tid = section_id;
+                let ts = timestamp / 1000;
+                self.event(format_args!(
+                    r#"{{"name": "Outlier {name}", "cat": "OUTLIER", "ph": "i", "s": "t", "pid": 1, "tid": {tid}, "ts": {ts}, "args": {{"duration": {duration}, "estimate": {estimate}}}}}"#
+                ));
+            }
+            ProfilerEvent::Estimate {
+                section_id,
+                duration,
+                estimate,
+                timestamp,
+                ..
+            } => {
+                let ts = timestamp / 1000;
+                self.event(format_args!(
+                    r#"{{"name": "Estimate {section_id}", "cat": "ESTIMATOR", "ph": "C", "pid": 1, "tid": "10000", "ts": {ts}, "args": {{"duration": {duration}, "estimate": {estimate}}}}}"#
+                ));
+            }
+            ProfilerEvent::Dropped { count, timestamp } => {
+                let ts = timestamp / 1000;
+                self.event(format_args!(
+                    r#"{{"name": "Dropped Events", "cat": "PROFILER", "ph": "C", "pid": 1, "tid": "10000", "ts": {ts}, "args": {{"dropped": {count}}}}}"#
+                ));
+            }
+        }
+    }
+}
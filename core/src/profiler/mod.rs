@@ -0,0 +1,467 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::config::PacingMode;
+use crate::{FrameId, Interval, MarkType, SectionId, Timestamp};
+
+mod binary;
+mod files;
+mod json;
+mod perfetto;
+mod record;
+mod ring;
+
+pub use binary::{BinaryReader, BinarySink};
+pub use files::{ProfilerConfig, TraceFormat};
+pub use json::JsonSink;
+pub use perfetto::PerfettoSink;
+pub use record::{CallbackSink, ProfilerCallback, ProfilerRecord};
+pub use ring::{RingReader, RingSink, MAX_RING_CAPACITY};
+
+/// Number of events that can be queued for the profiler thread before new events are dropped.
+const QUEUE_CAPACITY: usize = 4096;
+
+/// Something that happened in a context, as seen by the profiler. All times are in nanoseconds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProfilerEvent {
+    Mark {
+        frame_id: FrameId,
+        section_id: SectionId,
+        mark_type: MarkType,
+        timestamp: Timestamp,
+    },
+    Sleep {
+        frame_id: FrameId,
+        start_time: Timestamp,
+        end_time: Timestamp,
+    },
+    Latency {
+        frame_id: FrameId,
+        latency: Interval,
+        queueing_delay: Interval,
+        finish_time: Timestamp,
+    },
+    FrameTime {
+        frame_id: FrameId,
+        top_interval: Interval,
+        bop_interval: Interval,
+        finish_time: Timestamp,
+    },
+    Margin {
+        margin: Interval,
+        timestamp: Timestamp,
+    },
+    Mode {
+        mode: PacingMode,
+        latency_budget: Interval,
+        timestamp: Timestamp,
+    },
+    /// A section duration that was not fed to its estimator as is.
+    Outlier {
+        frame_id: FrameId,
+        section_id: SectionId,
+        duration: Interval,
+        estimate: Interval,
+        timestamp: Timestamp,
+    },
+    /// A section duration that was fed to its estimator, and the resulting estimate.
+    Estimate {
+        frame_id: FrameId,
+        section_id: SectionId,
+        duration: Interval,
+        estimate: Interval,
+        timestamp: Timestamp,
+    },
+    /// Total number of events dropped so far because the profiler thread fell behind.
+    Dropped { count: u64, timestamp: Timestamp },
+}
+
+impl ProfilerEvent {
+    pub fn timestamp(&self) -> Timestamp {
+        match *self {
+            ProfilerEvent::Mark { timestamp, .. }
+            | ProfilerEvent::Margin { timestamp, .. }
+            | ProfilerEvent::Mode { timestamp, .. }
+            | ProfilerEvent::Outlier { timestamp, .. }
+            | ProfilerEvent::Estimate { timestamp, .. }
+            | ProfilerEvent::Dropped { timestamp, .. } => timestamp,
+            ProfilerEvent::Latency { finish_time, .. }
+            | ProfilerEvent::FrameTime { finish_time, .. } => finish_time,
+            ProfilerEvent::Sleep { start_time, .. } => start_time,
+        }
+    }
+}
+
+/// Destination for profiling events. Sinks are called on the profiler thread, in the order the
+/// events happened.
+pub trait ProfilerSink: Send {
+    fn record(&mut self, event: &ProfilerEvent);
+}
+
+/// Records events for profiling.
+///
+/// Events are passed to the sinks by a background thread, so that a slow sink does not stall the
+/// caller. If the thread falls behind, events are dropped instead. Without sinks, no thread is
+/// started and events are discarded right away.
+pub struct Profiler {
+    /// `None` if there are no sinks.
+    queue: Option<SyncSender<ProfilerEvent>>,
+    /// Hands new sinks to the profiler thread. Unbounded, so that adding a sink never waits for
+    /// the thread to catch up with the events.
+    sinks: Option<Sender<Box<dyn ProfilerSink>>>,
+    dropped: Arc<AtomicU64>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Profiler {
+    /// Writes a trace if profiling is enabled in `config`.
+    pub fn new(config: &ProfilerConfig) -> Profiler {
+        let mut profiler = Profiler::disabled();
+        if !config.enabled {
+            return profiler;
+        }
+        let sink: Option<Box<dyn ProfilerSink>> = match config.format {
+            TraceFormat::Json => JsonSink::open(config).map(|sink| Box::new(sink) as _),
+            TraceFormat::Binary => BinarySink::open(config).map(|sink| Box::new(sink) as _),
+            TraceFormat::Perfetto => PerfettoSink::open(config).map(|sink| Box::new(sink) as _),
+        };
+        if let Some(sink) = sink {
+            profiler.add_sink(sink);
+        }
+        profiler
+    }
+
+    pub fn disabled() -> Profiler {
+        Profiler {
+            queue: None,
+            sinks: None,
+            dropped: Default::default(),
+            thread: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_writer(writer: impl std::io::Write + Send + 'static) -> Profiler {
+        let mut profiler = Profiler::disabled();
+        profiler.add_sink(Box::new(JsonSink::new(writer)));
+        profiler
+    }
+
+    /// Adds a sink that receives all events from now on. Events that are still queued when the
+    /// sink is added may be passed to it as well.
+    pub fn add_sink(&mut self, sink: Box<dyn ProfilerSink>) {
+        if self.queue.is_none() {
+            self.spawn();
+        }
+        if let Some(sinks) = &self.sinks {
+            if sinks.send(sink).is_err() {
+                self.queue = None;
+                self.sinks = None;
+            }
+        }
+    }
+
+    fn spawn(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let (queue, events) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let (sinks, new_sinks) = mpsc::channel();
+        let thread = {
+            let dropped = self.dropped.clone();
+            thread::Builder::new()
+                .name("lfx2-profiler".into())
+                .spawn(move || run(events, new_sinks, &dropped))
+        };
+        match thread {
+            Ok(thread) => {
+                self.queue = Some(queue);
+                self.sinks = Some(sinks);
+                self.thread = Some(thread);
+            }
+            Err(e) => {
+                eprintln!("LFX2 WARN: Failed to start profiler thread: {e}. Profiling is disabled.")
+            }
+        }
+    }
+
+    /// Number of events dropped because the profiler thread fell behind.
+    #[cfg(test)]
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn send(&mut self, event: ProfilerEvent) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        match queue.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    eprintln!("LFX2 WARN: Profiler cannot keep up, dropping events.");
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.queue = None;
+                self.sinks = None;
+            }
+        }
+    }
+
+    pub fn mark(
+        &mut self,
+        frame_id: FrameId,
+        section_id: SectionId,
+        mark_type: MarkType,
+        timestamp: Timestamp,
+    ) {
+        self.send(ProfilerEvent::Mark {
+            frame_id,
+            section_id,
+            mark_type,
+            timestamp,
+        });
+    }
+
+    pub fn latency(
+        &mut self,
+        frame_id: FrameId,
+        latency: Interval,
+        queueing_delay: Interval,
+        finish_time: Timestamp,
+    ) {
+        self.send(ProfilerEvent::Latency {
+            frame_id,
+            latency,
+            queueing_delay,
+            finish_time,
+        });
+    }
+
+    pub fn margin(&mut self, margin: Interval, timestamp: Timestamp) {
+        self.send(ProfilerEvent::Margin { margin, timestamp });
+    }
+
+    pub fn mode(&mut self, mode: PacingMode, latency_budget: Interval, timestamp: Timestamp) {
+        self.send(ProfilerEvent::Mode {
+            mode,
+            latency_budget,
+            timestamp,
+        });
+    }
+
+    pub fn frame_time(
+        &mut self,
+        frame_id: FrameId,
+        top_interval: Interval,
+        bop_interval: Interval,
+        finish_time: Timestamp,
+    ) {
+        self.send(ProfilerEvent::FrameTime {
+            frame_id,
+            top_interval,
+            bop_interval,
+            finish_time,
+        });
+    }
+
+    pub fn sleep(&mut self, frame_id: FrameId, start_time: Timestamp, end_time: Timestamp) {
+        self.send(ProfilerEvent::Sleep {
+            frame_id,
+            start_time,
+            end_time,
+        });
+    }
+}
+
+/// Lets a [`PacingPolicy`](crate::PacingPolicy) record events of its own, such as estimator
+/// updates.
+pub struct Recorder<'a> {
+    profiler: &'a mut Profiler,
+}
+
+impl Profiler {
+    pub fn recorder(&mut self) -> Recorder<'_> {
+        Recorder { profiler: self }
+    }
+}
+
+impl Recorder<'_> {
+    pub fn record(&mut self, event: ProfilerEvent) {
+        self.profiler.send(event);
+    }
+}
+
+impl Drop for Profiler {
+    /// Waits for the queued events to be passed to the sinks, and for the sinks to be dropped.
+    fn drop(&mut self) {
+        self.queue = None;
+        self.sinks = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Fans out events to the sinks, on the profiler thread.
+fn run(
+    events: Receiver<ProfilerEvent>,
+    new_sinks: Receiver<Box<dyn ProfilerSink>>,
+    dropped: &AtomicU64,
+) {
+    let mut sinks: Vec<Box<dyn ProfilerSink>> = Vec::new();
+    // Dropped events already reported to the sinks.
+    let mut reported = 0;
+    while let Ok(event) = events.recv() {
+        sinks.extend(new_sinks.try_iter());
+        let count = dropped.load(Ordering::Relaxed);
+        if count != reported {
+            reported = count;
+            let dropped = ProfilerEvent::Dropped {
+                count,
+                timestamp: event.timestamp(),
+            };
+            for sink in &mut sinks {
+                sink.record(&dropped);
+            }
+        }
+        for sink in &mut sinks {
+            sink.record(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::record::ProfilerRecordKind;
+    use super::*;
+    use std::fs;
+    use std::io::{self, Write};
+
+    #[test]
+    fn test_rotation_respects_quota() {
+        let directory = std::env::temp_dir().join(format!("lfx2-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let config = ProfilerConfig {
+            enabled: true,
+            format: TraceFormat::Json,
+            directory: Some(directory.clone()),
+            rotate_size: 1000,
+            rotate_interval: None,
+            quota: 4000,
+        };
+        let mut profiler = Profiler::new(&config);
+        for i in 0..1000 {
+            profiler.margin(i, i * 1000);
+        }
+        drop(profiler);
+
+        let traces: Vec<_> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+        fs::remove_dir_all(&directory).unwrap();
+        assert!((2..=4).contains(&traces.len()), "{}", traces.len());
+        for trace in traces {
+            assert!(
+                trace.starts_with("[\n") && trace.ends_with("\n]\n"),
+                "{trace}"
+            );
+        }
+    }
+
+    /// Collects everything written to it, for inspection after the writer is dropped.
+    #[derive(Clone, Default)]
+    pub(super) struct Shared(pub Arc<parking_lot::Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Blocks the first write until the test lets it through.
+    struct Gate(Option<mpsc::Receiver<()>>);
+
+    impl Write for Gate {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Some(gate) = self.0.take() {
+                let _ = gate.recv();
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_drops_events_when_writer_stalls() {
+        let (open, gate) = mpsc::channel();
+        let mut profiler = Profiler::with_writer(Gate(Some(gate)));
+        for i in 0..QUEUE_CAPACITY as u64 * 2 {
+            profiler.margin(i, i * 1000);
+        }
+        assert!(
+            profiler.dropped_events() >= QUEUE_CAPACITY as u64 / 2,
+            "{}",
+            profiler.dropped_events()
+        );
+        // Adding a sink doesn't wait for the queue to drain.
+        let (ring, reader) = RingSink::new(QUEUE_CAPACITY * 2);
+        profiler.add_sink(Box::new(ring));
+        open.send(()).unwrap();
+        drop(profiler);
+        assert!(!reader.take(usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_fans_out_to_all_sinks() {
+        let records = Arc::new(parking_lot::Mutex::new(Vec::<ProfilerRecord>::new()));
+        unsafe extern "C" fn collect(
+            user_data: *mut std::ffi::c_void,
+            record: *const ProfilerRecord,
+        ) {
+            let records = &*(user_data as *const parking_lot::Mutex<Vec<ProfilerRecord>>);
+            records.lock().push(*record);
+        }
+
+        let (ring, reader) = RingSink::new(2);
+        let mut profiler = Profiler::disabled();
+        profiler.add_sink(Box::new(ring));
+        profiler.add_sink(Box::new(unsafe {
+            CallbackSink::new(collect, Arc::as_ptr(&records) as *mut _)
+        }));
+        profiler.mark(FrameId(1), 0, MarkType::Begin, 1000);
+        profiler.recorder().record(ProfilerEvent::Estimate {
+            frame_id: FrameId(1),
+            section_id: 0,
+            duration: 500,
+            estimate: 400,
+            timestamp: 2000,
+        });
+        profiler.margin(100, 3000);
+        drop(profiler);
+
+        let events = reader.take(usize::MAX);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            events[0],
+            ProfilerEvent::Estimate { estimate: 400, .. }
+        ));
+        let records = records.lock();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].kind, ProfilerRecordKind::MarkBegin);
+        assert_eq!(records[1].values, [500, 400]);
+        assert_eq!(records[2], ProfilerRecord::from(&events[1]));
+    }
+}
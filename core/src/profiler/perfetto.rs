@@ -0,0 +1,534 @@
+use std::collections::HashSet;
+use std::io::{BufWriter, Write};
+
+use super::binary::write_varint;
+use super::files::{ProfilerConfig, TraceFiles};
+use super::{ProfilerEvent, ProfilerSink};
+use crate::{MarkType, Timestamp};
+
+// Field numbers and enum values from the protos in perfetto/protos/perfetto/trace.
+const TRACE_PACKET: u64 = 1;
+const PACKET_TIMESTAMP: u64 = 8;
+const PACKET_SEQUENCE_ID: u64 = 10;
+const PACKET_TRACK_EVENT: u64 = 11;
+const PACKET_SEQUENCE_FLAGS: u64 = 13;
+const PACKET_TRACK_DESCRIPTOR: u64 = 60;
+const TRACK_UUID: u64 = 1;
+const TRACK_NAME: u64 = 2;
+const TRACK_PARENT_UUID: u64 = 5;
+const TRACK_COUNTER: u64 = 8;
+const COUNTER_UNIT: u64 = 3;
+const EVENT_DEBUG_ANNOTATIONS: u64 = 4;
+const EVENT_TYPE: u64 = 9;
+const EVENT_TRACK_UUID: u64 = 11;
+const EVENT_NAME: u64 = 23;
+const EVENT_COUNTER_VALUE: u64 = 30;
+const EVENT_FLOW_IDS: u64 = 47;
+const ANNOTATION_UINT_VALUE: u64 = 3;
+const ANNOTATION_NAME: u64 = 10;
+
+const SEQ_INCREMENTAL_STATE_CLEARED: u64 = 1;
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+const TYPE_INSTANT: u64 = 3;
+const TYPE_COUNTER: u64 = 4;
+const UNIT_TIME_NS: u64 = 1;
+const UNIT_COUNT: u64 = 2;
+
+/// All packets are written by the profiler thread, as a single sequence.
+const SEQUENCE_ID: u64 = 1;
+
+const ROOT_TRACK: u64 = 1;
+const SLEEP_TRACK: u64 = 2;
+const LATENCY_TRACK: u64 = 3;
+const QUEUEING_DELAY_TRACK: u64 = 4;
+const TOP_INTERVAL_TRACK: u64 = 5;
+const BOP_INTERVAL_TRACK: u64 = 6;
+const MARGIN_TRACK: u64 = 7;
+const DROPPED_TRACK: u64 = 8;
+/// Each section has a track at this offset plus its id, and an estimate counter track at
+/// [`ESTIMATE_TRACKS`] plus its id.
+const SECTION_TRACKS: u64 = 1 << 32;
+const ESTIMATE_TRACKS: u64 = 2 << 32;
+
+/// Name and counter unit of a track.
+fn describe_track(uuid: u64) -> (String, Option<u64>) {
+    match uuid {
+        ROOT_TRACK => ("LatencyFleX 2".into(), None),
+        SLEEP_TRACK => ("Sleep".into(), None),
+        LATENCY_TRACK => ("Latency".into(), Some(UNIT_TIME_NS)),
+        QUEUEING_DELAY_TRACK => ("Queueing Delay".into(), Some(UNIT_TIME_NS)),
+        TOP_INTERVAL_TRACK => ("Frame Time (Begin)".into(), Some(UNIT_TIME_NS)),
+        BOP_INTERVAL_TRACK => ("Frame Time (End)".into(), Some(UNIT_TIME_NS)),
+        MARGIN_TRACK => ("Margin".into(), Some(UNIT_TIME_NS)),
+        DROPPED_TRACK => ("Dropped Events".into(), Some(UNIT_COUNT)),
+        uuid if uuid >= ESTIMATE_TRACKS => (
+            format!("Estimate {}", uuid - ESTIMATE_TRACKS),
+            Some(UNIT_TIME_NS),
+        ),
+        uuid => (format!("Section {}", uuid - SECTION_TRACKS), None),
+    }
+}
+
+fn key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(buf, field << 3 | wire_type);
+}
+
+fn varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    key(buf, field, 0);
+    write_varint(buf, value);
+}
+
+fn fixed64_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    key(buf, field, 1);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    key(buf, field, 2);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn message_field(buf: &mut Vec<u8>, field: u64, message: impl FnOnce(&mut Vec<u8>)) {
+    let mut nested = Vec::new();
+    message(&mut nested);
+    bytes_field(buf, field, &nested);
+}
+
+/// Writes events as a Perfetto protobuf trace, which keeps sections, sleeps and counters on
+/// tracks of their own and links the sections of each frame with flow arrows.
+///
+/// Like the binary format, a trace that is cut short can still be read up to its last complete
+/// packet.
+pub struct PerfettoSink {
+    /// `None` if writing failed.
+    output: Option<BufWriter<Box<dyn Write + Send>>>,
+    files: Option<TraceFiles>,
+    /// Bytes written to the current trace.
+    written: u64,
+    /// Tracks whose descriptors were written to the current trace.
+    described: HashSet<u64>,
+    is_first_packet: bool,
+    /// Scratch buffer for encoding packets.
+    buf: Vec<u8>,
+}
+
+impl PerfettoSink {
+    pub fn new(writer: impl Write + Send + 'static) -> PerfettoSink {
+        let mut sink = PerfettoSink {
+            output: None,
+            files: None,
+            written: 0,
+            described: HashSet::new(),
+            is_first_packet: true,
+            buf: Vec::new(),
+        };
+        sink.start(writer);
+        sink
+    }
+
+    /// Writes rotating traces to the configured directory, or returns `None` after printing a
+    /// warning.
+    pub fn open(config: &ProfilerConfig) -> Option<PerfettoSink> {
+        let (files, file) = TraceFiles::open(config, "pftrace")?;
+        let mut sink = PerfettoSink::new(file);
+        sink.files = Some(files);
+        Some(sink)
+    }
+
+    /// Starts a new trace.
+    fn start(&mut self, writer: impl Write + Send + 'static) {
+        self.output = Some(BufWriter::new(Box::new(writer) as Box<dyn Write + Send>));
+        self.written = 0;
+        self.described.clear();
+        self.is_first_packet = true;
+    }
+
+    fn rotate(&mut self) {
+        let Some(files) = &mut self.files else {
+            return;
+        };
+        match files.next() {
+            Ok(file) => {
+                self.finish();
+                self.start(file);
+            }
+            Err(e) => {
+                eprintln!("LFX2 WARN: Failed to rotate trace: {e}. Profiling is disabled.");
+                self.finish();
+                self.files = None;
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(output) = &mut self.output {
+            let _ = output.flush();
+        }
+        self.output = None;
+    }
+
+    fn packet(
+        &mut self,
+        timestamp: Option<Timestamp>,
+        field: u64,
+        body: impl FnOnce(&mut Vec<u8>),
+    ) {
+        let Some(output) = &mut self.output else {
+            return;
+        };
+        let mut packet = Vec::new();
+        if let Some(timestamp) = timestamp {
+            varint_field(&mut packet, PACKET_TIMESTAMP, timestamp);
+        }
+        varint_field(&mut packet, PACKET_SEQUENCE_ID, SEQUENCE_ID);
+        if self.is_first_packet {
+            self.is_first_packet = false;
+            varint_field(
+                &mut packet,
+                PACKET_SEQUENCE_FLAGS,
+                SEQ_INCREMENTAL_STATE_CLEARED,
+            );
+        }
+        message_field(&mut packet, field, body);
+
+        self.buf.clear();
+        bytes_field(&mut self.buf, TRACE_PACKET, &packet);
+        let _ = output.write_all(&self.buf);
+        self.written += self.buf.len() as u64;
+    }
+
+    /// Writes the descriptor of a track, and of its parent, if not done yet in this trace.
+    fn describe(&mut self, uuid: u64) {
+        if uuid != ROOT_TRACK {
+            self.describe(ROOT_TRACK);
+        }
+        if !self.described.insert(uuid) {
+            return;
+        }
+        let (name, unit) = describe_track(uuid);
+        self.packet(None, PACKET_TRACK_DESCRIPTOR, |buf| {
+            varint_field(buf, TRACK_UUID, uuid);
+            bytes_field(buf, TRACK_NAME, name.as_bytes());
+            if uuid != ROOT_TRACK {
+                varint_field(buf, TRACK_PARENT_UUID, ROOT_TRACK);
+            }
+            if let Some(unit) = unit {
+                message_field(buf, TRACK_COUNTER, |buf| {
+                    varint_field(buf, COUNTER_UNIT, unit)
+                });
+            }
+        });
+    }
+
+    fn track_event(
+        &mut self,
+        timestamp: Timestamp,
+        track: u64,
+        event_type: u64,
+        event: impl FnOnce(&mut Vec<u8>),
+    ) {
+        self.describe(track);
+        self.packet(Some(timestamp), PACKET_TRACK_EVENT, |buf| {
+            varint_field(buf, EVENT_TYPE, event_type);
+            varint_field(buf, EVENT_TRACK_UUID, track);
+            event(buf);
+        });
+    }
+
+    fn slice_begin(&mut self, timestamp: Timestamp, track: u64, name: &str, flow: Option<u64>) {
+        self.track_event(timestamp, track, TYPE_SLICE_BEGIN, |buf| {
+            bytes_field(buf, EVENT_NAME, name.as_bytes());
+            if let Some(flow) = flow {
+                fixed64_field(buf, EVENT_FLOW_IDS, flow);
+            }
+        });
+    }
+
+    fn slice_end(&mut self, timestamp: Timestamp, track: u64) {
+        self.track_event(timestamp, track, TYPE_SLICE_END, |_| {});
+    }
+
+    fn instant(&mut self, timestamp: Timestamp, track: u64, name: &str, args: &[(&str, u64)]) {
+        self.track_event(timestamp, track, TYPE_INSTANT, |buf| {
+            bytes_field(buf, EVENT_NAME, name.as_bytes());
+            for &(name, value) in args {
+                message_field(buf, EVENT_DEBUG_ANNOTATIONS, |buf| {
+                    bytes_field(buf, ANNOTATION_NAME, name.as_bytes());
+                    varint_field(buf, ANNOTATION_UINT_VALUE, value);
+                });
+            }
+        });
+    }
+
+    fn counter(&mut self, timestamp: Timestamp, track: u64, value: u64) {
+        self.track_event(timestamp, track, TYPE_COUNTER, |buf| {
+            varint_field(buf, EVENT_COUNTER_VALUE, value);
+        });
+    }
+}
+
+impl Drop for PerfettoSink {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl ProfilerSink for PerfettoSink {
+    fn record(&mut self, event: &ProfilerEvent) {
+        // Rotate between events, so that the packets of an event and the descriptors of its
+        // tracks end up in the same trace.
+        if self
+            .files
+            .as_ref()
+            .is_some_and(|files| files.due(self.written))
+        {
+            self.rotate();
+        }
+        match *event {
+            ProfilerEvent::Mark {
+                frame_id,
+                section_id,
+                mark_type,
+                timestamp,
+            } => {
+                let track = SECTION_TRACKS + u64::from(section_id);
+                match mark_type {
+                    // Flow ids must not be zero.
+                    MarkType::Begin => self.slice_begin(
+                        timestamp,
+                        track,
+                        &frame_id.0.to_string(),
+                        Some(frame_id.0 + 1),
+                    ),
+                    MarkType::End => self.slice_end(timestamp, track),
+                }
+            }
+            ProfilerEvent::Sleep {
+                start_time,
+                end_time,
+                ..
+            } => {
+                self.slice_begin(start_time, SLEEP_TRACK, "Sleep", None);
+                self.slice_end(end_time, SLEEP_TRACK);
+            }
+            ProfilerEvent::Latency {
+                latency,
+                queueing_delay,
+                finish_time,
+                ..
+            } => {
+                self.counter(finish_time, LATENCY_TRACK, latency);
+                self.counter(finish_time, QUEUEING_DELAY_TRACK, queueing_delay);
+            }
+            ProfilerEvent::FrameTime {
+                top_interval,
+                bop_interval,
+                finish_time,
+                ..
+            } => {
+                self.counter(finish_time, TOP_INTERVAL_TRACK, top_interval);
+                self.counter(finish_time, BOP_INTERVAL_TRACK, bop_interval);
+            }
+            ProfilerEvent::Margin { margin, timestamp } => {
+                self.counter(timestamp, MARGIN_TRACK, margin)
+            }
+            ProfilerEvent::Mode {
+                mode,
+                latency_budget,
+                timestamp,
+            } => self.instant(
+                timestamp,
+                ROOT_TRACK,
+                &format!("Mode {mode:?}"),
+                &[("latency_budget", latency_budget)],
+            ),
+            ProfilerEvent::Outlier {
+                frame_id,
+                section_id,
+                duration,
+                estimate,
+                timestamp,
+            } => self.instant(
+                timestamp,
+                SECTION_TRACKS + u64::from(section_id),
+                &format!("Outlier {}", frame_id.0),
+                &[("duration", duration), ("estimate", estimate)],
+            ),
+            ProfilerEvent::Estimate {
+                section_id,
+                estimate,
+                timestamp,
+                ..
+            } => self.counter(timestamp, ESTIMATE_TRACKS + u64::from(section_id), estimate),
+            ProfilerEvent::Dropped { count, timestamp } => {
+                self.counter(timestamp, DROPPED_TRACK, count)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::profiler::binary::read_varint;
+    use crate::profiler::tests::Shared;
+    use crate::FrameId;
+
+    #[derive(Debug)]
+    enum Value<'a> {
+        Varint(u64),
+        Fixed64(u64),
+        Bytes(&'a [u8]),
+    }
+
+    /// Decodes the fields of a protobuf message, checking that it is well-formed.
+    fn parse(mut buf: &[u8]) -> Vec<(u64, Value<'_>)> {
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = read_varint(&mut buf).unwrap();
+            let value = match key & 7 {
+                0 => Value::Varint(read_varint(&mut buf).unwrap()),
+                1 => {
+                    let (value, rest) = buf.split_at(8);
+                    buf = rest;
+                    Value::Fixed64(u64::from_le_bytes(value.try_into().unwrap()))
+                }
+                2 => {
+                    let length = read_varint(&mut buf).unwrap() as usize;
+                    let (value, rest) = buf.split_at(length);
+                    buf = rest;
+                    Value::Bytes(value)
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.push((key >> 3, value));
+        }
+        fields
+    }
+
+    fn varint(fields: &[(u64, Value)], field: u64) -> Option<u64> {
+        fields.iter().find_map(|(number, value)| match value {
+            Value::Varint(value) if *number == field => Some(*value),
+            _ => None,
+        })
+    }
+
+    fn bytes<'a>(fields: &[(u64, Value<'a>)], field: u64) -> Option<&'a [u8]> {
+        fields.iter().find_map(|(number, value)| match value {
+            Value::Bytes(value) if *number == field => Some(*value),
+            _ => None,
+        })
+    }
+
+    #[derive(Debug, Default)]
+    struct TrackEvent {
+        timestamp: u64,
+        event_type: u64,
+        name: String,
+        flow_ids: Vec<u64>,
+        counter_value: Option<u64>,
+    }
+
+    #[test]
+    fn test_trace_parses_back() {
+        let buffer = Shared::default();
+        let mut sink = PerfettoSink::new(buffer.clone());
+        for (section_id, begin) in [(0, 1000), (1, 3000)] {
+            let mark = |mark_type, timestamp| ProfilerEvent::Mark {
+                frame_id: FrameId(7),
+                section_id,
+                mark_type,
+                timestamp,
+            };
+            sink.record(&mark(MarkType::Begin, begin));
+            sink.record(&mark(MarkType::End, begin + 1500));
+        }
+        sink.record(&ProfilerEvent::Sleep {
+            frame_id: FrameId(8),
+            start_time: 5000,
+            end_time: 6000,
+        });
+        sink.record(&ProfilerEvent::Latency {
+            frame_id: FrameId(7),
+            latency: 3500,
+            queueing_delay: 200,
+            finish_time: 4500,
+        });
+        sink.record(&ProfilerEvent::FrameTime {
+            frame_id: FrameId(7),
+            top_interval: 16_000,
+            bop_interval: 15_000,
+            finish_time: 4500,
+        });
+        drop(sink);
+        let trace = buffer.0.lock().clone();
+
+        // Track names by uuid, and whether they are counter tracks.
+        let mut tracks = HashMap::new();
+        let mut events: HashMap<String, Vec<TrackEvent>> = HashMap::new();
+        let packets = parse(&trace);
+        for (i, (field, packet)) in packets.iter().enumerate() {
+            assert_eq!(*field, TRACE_PACKET);
+            let Value::Bytes(packet) = packet else {
+                panic!("{packet:?}");
+            };
+            let packet = parse(packet);
+            assert_eq!(varint(&packet, PACKET_SEQUENCE_ID), Some(SEQUENCE_ID));
+            assert_eq!(varint(&packet, PACKET_SEQUENCE_FLAGS).is_some(), i == 0);
+            if let Some(descriptor) = bytes(&packet, PACKET_TRACK_DESCRIPTOR) {
+                let descriptor = parse(descriptor);
+                let name = String::from_utf8(bytes(&descriptor, TRACK_NAME).unwrap().to_vec());
+                let is_counter = bytes(&descriptor, TRACK_COUNTER).is_some();
+                let uuid = varint(&descriptor, TRACK_UUID).unwrap();
+                assert!(tracks.insert(uuid, (name.unwrap(), is_counter)).is_none());
+                continue;
+            }
+            let event = parse(bytes(&packet, PACKET_TRACK_EVENT).unwrap());
+            let (track, is_counter) = &tracks[&varint(&event, EVENT_TRACK_UUID).unwrap()];
+            let event_type = varint(&event, EVENT_TYPE).unwrap();
+            assert_eq!(*is_counter, event_type == TYPE_COUNTER);
+            events.entry(track.clone()).or_default().push(TrackEvent {
+                timestamp: varint(&packet, PACKET_TIMESTAMP).unwrap(),
+                event_type,
+                name: bytes(&event, EVENT_NAME)
+                    .map(|name| String::from_utf8(name.to_vec()).unwrap())
+                    .unwrap_or_default(),
+                flow_ids: event
+                    .iter()
+                    .filter_map(|(field, value)| match value {
+                        Value::Fixed64(id) if *field == EVENT_FLOW_IDS => Some(*id),
+                        _ => None,
+                    })
+                    .collect(),
+                counter_value: varint(&event, EVENT_COUNTER_VALUE),
+            });
+        }
+
+        let [first, second] = ["Section 0", "Section 1"].map(|track| &events[track]);
+        for section in [first, second] {
+            assert_eq!(section.len(), 2);
+            assert_eq!(section[0].event_type, TYPE_SLICE_BEGIN);
+            assert_eq!(section[0].name, "7");
+            assert_eq!(section[1].event_type, TYPE_SLICE_END);
+        }
+        assert_eq!(first[0].timestamp, 1000);
+        assert_eq!(second[1].timestamp, 4500);
+        assert_eq!(first[0].flow_ids.len(), 1);
+        assert_eq!(first[0].flow_ids, second[0].flow_ids);
+
+        let sleep = &events["Sleep"];
+        assert_eq!((sleep[0].timestamp, sleep[1].timestamp), (5000, 6000));
+        for (track, value) in [
+            ("Latency", 3500),
+            ("Queueing Delay", 200),
+            ("Frame Time (Begin)", 16_000),
+            ("Frame Time (End)", 15_000),
+        ] {
+            assert_eq!(events[track][0].counter_value, Some(value), "{track}");
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::ffi::c_void;
+
+use super::binary::{read_varint, write_varint};
+use super::{ProfilerEvent, ProfilerSink};
+use crate::config::PacingMode;
+use crate::{FrameId, MarkType, SectionId, Timestamp};
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProfilerRecordKind {
+    MarkBegin,
+    MarkEnd,
+    /// `values` holds the end of the sleep.
+    Sleep,
+    /// `values` holds the latency and the queueing delay.
+    Latency,
+    /// `values` holds the interval between the beginnings and between the ends of this frame
+    /// and the previous one.
+    FrameTime,
+    /// `values` holds the margin.
+    Margin,
+    /// `values` holds the `lfx2PacingMode` and the latency budget.
+    Mode,
+    /// `values` holds the rejected duration and the estimate it was compared to.
+    Outlier,
+    /// `values` holds the measured duration and the updated estimate.
+    Estimate,
+    /// `values` holds the number of events dropped so far.
+    Dropped,
+}
+
+impl ProfilerRecordKind {
+    fn from_raw(raw: u64) -> Option<ProfilerRecordKind> {
+        use ProfilerRecordKind::*;
+        [
+            MarkBegin, MarkEnd, Sleep, Latency, FrameTime, Margin, Mode, Outlier, Estimate, Dropped,
+        ]
+        .get(usize::try_from(raw).ok()?)
+        .copied()
+    }
+}
+
+/// A profiling event in a fixed layout for the C API.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ProfilerRecord {
+    pub kind: ProfilerRecordKind,
+    /// Zero if the event does not belong to a frame.
+    pub frame_id: u64,
+    /// Zero if the event does not belong to a section.
+    pub section_id: SectionId,
+    pub timestamp: Timestamp,
+    /// Interpreted according to `kind`. Unused values are zero.
+    pub values: [u64; 2],
+}
+
+impl ProfilerRecord {
+    /// Converts the record back to an event, or returns `None` if a value is out of range.
+    pub fn event(&self) -> Option<ProfilerEvent> {
+        let frame_id = FrameId(self.frame_id);
+        let section_id = self.section_id;
+        let timestamp = self.timestamp;
+        let [first, second] = self.values;
+        Some(match self.kind {
+            ProfilerRecordKind::MarkBegin | ProfilerRecordKind::MarkEnd => ProfilerEvent::Mark {
+                frame_id,
+                section_id,
+                mark_type: match self.kind {
+                    ProfilerRecordKind::MarkBegin => MarkType::Begin,
+                    _ => MarkType::End,
+                },
+                timestamp,
+            },
+            ProfilerRecordKind::Sleep => ProfilerEvent::Sleep {
+                frame_id,
+                start_time: timestamp,
+                end_time: first,
+            },
+            ProfilerRecordKind::Latency => ProfilerEvent::Latency {
+                frame_id,
+                latency: first,
+                queueing_delay: second,
+                finish_time: timestamp,
+            },
+            ProfilerRecordKind::FrameTime => ProfilerEvent::FrameTime {
+                frame_id,
+                top_interval: first,
+                bop_interval: second,
+                finish_time: timestamp,
+            },
+            ProfilerRecordKind::Margin => ProfilerEvent::Margin {
+                margin: first,
+                timestamp,
+            },
+            ProfilerRecordKind::Mode => ProfilerEvent::Mode {
+                mode: match first {
+                    0 => PacingMode::MinimumLatency,
+                    1 => PacingMode::Balanced,
+                    2 => PacingMode::Throughput,
+                    _ => return None,
+                },
+                latency_budget: second,
+                timestamp,
+            },
+            ProfilerRecordKind::Outlier => ProfilerEvent::Outlier {
+                frame_id,
+                section_id,
+                duration: first,
+                estimate: second,
+                timestamp,
+            },
+            ProfilerRecordKind::Estimate => ProfilerEvent::Estimate {
+                frame_id,
+                section_id,
+                duration: first,
+                estimate: second,
+                timestamp,
+            },
+            ProfilerRecordKind::Dropped => ProfilerEvent::Dropped {
+                count: first,
+                timestamp,
+            },
+        })
+    }
+
+    /// Appends the record to `buf` as varints, in declaration order.
+    pub(super) fn encode(&self, buf: &mut Vec<u8>) {
+        write_varint(buf, self.kind as u64);
+        write_varint(buf, self.frame_id);
+        write_varint(buf, self.section_id.into());
+        write_varint(buf, self.timestamp);
+        for value in self.values {
+            write_varint(buf, value);
+        }
+    }
+
+    /// Inverse of [`ProfilerRecord::encode`]. Returns `None` if the kind is unknown or the record
+    /// is malformed.
+    pub(super) fn decode(mut buf: &[u8]) -> Option<ProfilerRecord> {
+        let buf = &mut buf;
+        Some(ProfilerRecord {
+            kind: ProfilerRecordKind::from_raw(read_varint(buf).ok()?)?,
+            frame_id: read_varint(buf).ok()?,
+            section_id: read_varint(buf).ok()?.try_into().ok()?,
+            timestamp: read_varint(buf).ok()?,
+            values: [read_varint(buf).ok()?, read_varint(buf).ok()?],
+        })
+    }
+}
+
+impl From<&ProfilerEvent> for ProfilerRecord {
+    fn from(event: &ProfilerEvent) -> Self {
+        let record = |kind, frame_id: u64, section_id, timestamp, values| ProfilerRecord {
+            kind,
+            frame_id,
+            section_id,
+            timestamp,
+            values,
+        };
+        match *event {
+            ProfilerEvent::Mark {
+                frame_id,
+                section_id,
+                mark_type,
+                timestamp,
+            } => {
+                let kind = match mark_type {
+                    MarkType::Begin => ProfilerRecordKind::MarkBegin,
+                    MarkType::End => ProfilerRecordKind::MarkEnd,
+                };
+                record(kind, frame_id.0, section_id, timestamp, [0, 0])
+            }
+            ProfilerEvent::Sleep {
+                frame_id,
+                start_time,
+                end_time,
+            } => record(
+                ProfilerRecordKind::Sleep,
+                frame_id.0,
+                0,
+                start_time,
+                [end_time, 0],
+            ),
+            ProfilerEvent::Latency {
+                frame_id,
+                latency,
+                queueing_delay,
+                finish_time,
+            } => record(
+                ProfilerRecordKind::Latency,
+                frame_id.0,
+                0,
+                finish_time,
+                [latency, queueing_delay],
+            ),
+            ProfilerEvent::FrameTime {
+                frame_id,
+                top_interval,
+                bop_interval,
+                finish_time,
+            } => record(
+                ProfilerRecordKind::FrameTime,
+                frame_id.0,
+                0,
+                finish_time,
+                [top_interval, bop_interval],
+            ),
+            ProfilerEvent::Margin { margin, timestamp } => {
+                record(ProfilerRecordKind::Margin, 0, 0, timestamp, [margin, 0])
+            }
+            ProfilerEvent::Mode {
+                mode,
+                latency_budget,
+                timestamp,
+            } => record(
+                ProfilerRecordKind::Mode,
+                0,
+                0,
+                timestamp,
+                [mode as u64, latency_budget],
+            ),
+            ProfilerEvent::Outlier {
+                frame_id,
+                section_id,
+                duration,
+                estimate,
+                timestamp,
+            } => record(
+                ProfilerRecordKind::Outlier,
+                frame_id.0,
+                section_id,
+                timestamp,
+                [duration, estimate],
+            ),
+            ProfilerEvent::Estimate {
+                frame_id,
+                section_id,
+                duration,
+                estimate,
+                timestamp,
+            } => record(
+                ProfilerRecordKind::Estimate,
+                frame_id.0,
+                section_id,
+                timestamp,
+                [duration, estimate],
+            ),
+            ProfilerEvent::Dropped { count, timestamp } => {
+                record(ProfilerRecordKind::Dropped, 0, 0, timestamp, [count, 0])
+            }
+        }
+    }
+}
+
+pub type ProfilerCallback =
+    unsafe extern "C" fn(user_data: *mut c_void, record: *const ProfilerRecord);
+
+/// Passes each event to a function provided by the application.
+pub struct CallbackSink {
+    callback: ProfilerCallback,
+    user_data: *mut c_void,
+}
+
+// The application is responsible for making `user_data` usable from the profiler thread.
+unsafe impl Send for CallbackSink {}
+
+impl CallbackSink {
+    /// `callback` is called on the profiler thread, and must not call back into the context.
+    pub unsafe fn new(callback: ProfilerCallback, user_data: *mut c_void) -> CallbackSink {
+        CallbackSink {
+            callback,
+            user_data,
+        }
+    }
+}
+
+impl ProfilerSink for CallbackSink {
+    fn record(&mut self, event: &ProfilerEvent) {
+        let record = ProfilerRecord::from(event);
+        unsafe { (self.callback)(self.user_data, &record) }
+    }
+}
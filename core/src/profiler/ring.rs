@@ -0,0 +1,61 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use super::{ProfilerEvent, ProfilerSink};
+
+/// Largest number of events a ring can keep.
+pub const MAX_RING_CAPACITY: usize = 1 << 20;
+
+/// Number of events allocated up front. Larger rings grow as events arrive.
+const PREALLOCATED: usize = 1024;
+
+struct Ring {
+    events: VecDeque<ProfilerEvent>,
+    capacity: usize,
+}
+
+/// Keeps the latest events in memory, overwriting the oldest ones once full.
+pub struct RingSink {
+    ring: Arc<Mutex<Ring>>,
+}
+
+/// Reads the events kept by a [`RingSink`].
+pub struct RingReader {
+    ring: Arc<Mutex<Ring>>,
+}
+
+impl RingSink {
+    /// `capacity` is capped at [`MAX_RING_CAPACITY`].
+    pub fn new(capacity: usize) -> (RingSink, RingReader) {
+        let capacity = capacity.min(MAX_RING_CAPACITY);
+        let ring = Arc::new(Mutex::new(Ring {
+            events: VecDeque::with_capacity(capacity.min(PREALLOCATED)),
+            capacity,
+        }));
+        (RingSink { ring: ring.clone() }, RingReader { ring })
+    }
+}
+
+impl ProfilerSink for RingSink {
+    fn record(&mut self, event: &ProfilerEvent) {
+        let mut ring = self.ring.lock();
+        if ring.capacity == 0 {
+            return;
+        }
+        if ring.events.len() == ring.capacity {
+            ring.events.pop_front();
+        }
+        ring.events.push_back(*event);
+    }
+}
+
+impl RingReader {
+    /// Removes and returns up to `max` of the oldest events.
+    pub fn take(&self, max: usize) -> Vec<ProfilerEvent> {
+        let mut ring = self.ring.lock();
+        let count = max.min(ring.events.len());
+        ring.events.drain(..count).collect()
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{BTreeSet, VecDeque};
+
+use crate::config::ContextConfig;
+use crate::estimator::Estimator;
+use crate::Interval;
+
+/// Sample value as ordered bits, disambiguated by its sequence number.
+type Key = (u64, u64);
+
+/// Tracks a quantile of the most recent samples.
+///
+/// The window is counted in samples, so the elapsed time between samples is not used.
+///
+/// The window is split into two ordered sets, `lower` holding the smallest `rank` samples and
+/// `upper` the rest, so the quantile is the largest element of `lower`. Inserting, expiring and
+/// rebalancing each touch O(1) elements, making updates O(log n).
+pub struct QuantileEstimator {
+    quantile: f64,
+    window: usize,
+    seq: u64,
+    sum: f64,
+    square_sum: f64,
+    samples: VecDeque<Key>,
+    lower: BTreeSet<Key>,
+    upper: BTreeSet<Key>,
+}
+
+impl QuantileEstimator {
+    pub fn new(quantile: f64, window: usize) -> QuantileEstimator {
+        QuantileEstimator {
+            quantile,
+            window,
+            seq: 0,
+            sum: 0.,
+            square_sum: 0.,
+            samples: VecDeque::with_capacity(window + 1),
+            lower: BTreeSet::new(),
+            upper: BTreeSet::new(),
+        }
+    }
+
+    fn expire(&mut self) {
+        while self.samples.len() > self.window {
+            let key = self.samples.pop_front().unwrap();
+            let v = f64::from_bits(key.0);
+            self.sum -= v;
+            self.square_sum -= v * v;
+            if !self.lower.remove(&key) {
+                self.upper.remove(&key);
+            }
+        }
+    }
+
+    fn rebalance(&mut self) {
+        let rank = ((self.quantile * self.samples.len() as f64).ceil() as usize)
+            .clamp(1, self.samples.len().max(1));
+        while self.lower.len() > rank {
+            let key = self.lower.pop_last().unwrap();
+            self.upper.insert(key);
+        }
+        while self.lower.len() < rank {
+            match self.upper.pop_first() {
+                Some(key) => self.lower.insert(key),
+                None => break,
+            };
+        }
+    }
+}
+
+impl Estimator for QuantileEstimator {
+    fn configure(&mut self, config: &ContextConfig) {
+        self.quantile = config.quantile;
+        self.window = config.quantile_window as usize;
+        self.expire();
+        self.rebalance();
+    }
+
+    fn update(&mut self, v: f64, _elapsed: Option<Interval>) {
+        // The bit pattern of a non-negative float sorts like the float itself.
+        let v = v.max(0.);
+        let key = (v.to_bits(), self.seq);
+        self.seq += 1;
+        self.sum += v;
+        self.square_sum += v * v;
+
+        self.samples.push_back(key);
+        match self.lower.last() {
+            Some(&max) if key > max => self.upper.insert(key),
+            _ => self.lower.insert(key),
+        };
+        self.expire();
+        self.rebalance();
+    }
+
+    fn get(&self) -> f64 {
+        self.lower
+            .last()
+            .map(|&(bits, _)| f64::from_bits(bits))
+            .unwrap_or(0.)
+    }
+
+    fn variance(&self) -> f64 {
+        let n = self.samples.len() as f64;
+        if n == 0. {
+            0.
+        } else {
+            let mean = self.sum / n;
+            (self.square_sum / n - mean * mean).max(0.)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_sorted_window() {
+        const WINDOW: usize = 10;
+        let mut estimator = QuantileEstimator::new(0.9, WINDOW);
+        let mut history = Vec::new();
+        let mut x: u64 = 12345;
+        for _ in 0..1000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let v = (x >> 44) as f64;
+            estimator.update(v, None);
+            history.push(v);
+
+            let mut window = history[history.len().saturating_sub(WINDOW)..].to_vec();
+            window.sort_by(f64::total_cmp);
+            let rank = (0.9 * window.len() as f64).ceil() as usize;
+            assert_eq!(estimator.get(), window[rank - 1]);
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use std::hint;
 use std::mem;
 use std::num::NonZeroU64;
+use std::thread;
+use std::time::Duration;
 
 use once_cell::sync::Lazy;
 #[cfg(feature = "vulkan")]
@@ -25,12 +27,21 @@ pub fn timestamp_from_vulkan(calibration: u64) -> u64 {
 }
 
 pub fn timestamp_from_qpc(qpc: u64) -> Timestamp {
+    // The frequency Windows 10 and later report on every machine.
+    const FALLBACK_QPF: NonZeroU64 = match NonZeroU64::new(10_000_000) {
+        Some(qpf) => qpf,
+        None => unreachable!(),
+    };
     static QPF: Lazy<NonZeroU64> = Lazy::new(|| {
         let mut qpf = 0i64;
-        unsafe {
-            QueryPerformanceFrequency(&mut qpf);
+        let ok = unsafe { QueryPerformanceFrequency(&mut qpf) }.as_bool();
+        match NonZeroU64::new(qpf as u64) {
+            Some(qpf) if ok => qpf,
+            _ => {
+                eprintln!("LFX2 WARN: QueryPerformanceFrequency failed; assuming 10 MHz");
+                FALLBACK_QPF
+            }
         }
-        NonZeroU64::new(qpf as u64).unwrap()
     });
 
     let denom = 1_000_000_000;
@@ -50,18 +61,25 @@ pub fn timestamp_now() -> Timestamp {
 struct WaitableTimer(HANDLE);
 
 impl WaitableTimer {
-    fn new() -> WaitableTimer {
-        WaitableTimer(
-            unsafe {
-                CreateWaitableTimerExW(
-                    None,
-                    None,
-                    CREATE_WAITABLE_TIMER_HIGH_RESOLUTION,
-                    TIMER_ALL_ACCESS.0,
-                )
-            }
-            .unwrap(),
-        )
+    fn new() -> Option<WaitableTimer> {
+        unsafe {
+            CreateWaitableTimerExW(
+                None,
+                None,
+                CREATE_WAITABLE_TIMER_HIGH_RESOLUTION,
+                TIMER_ALL_ACCESS.0,
+            )
+        }
+        .ok()
+        .map(WaitableTimer)
+    }
+
+    /// Waits for `duration`, given in negative 100 ns units as the timer expects.
+    fn wait(&self, duration: i64) -> bool {
+        unsafe {
+            SetWaitableTimer(self.0, &duration, 0, None, None, false).as_bool()
+                && WaitForSingleObject(self.0, INFINITE).ok().is_ok()
+        }
     }
 }
 
@@ -74,7 +92,7 @@ impl Drop for WaitableTimer {
 }
 
 thread_local! {
-    static TIMER: WaitableTimer = WaitableTimer::new();
+    static TIMER: Option<WaitableTimer> = WaitableTimer::new();
 }
 
 static NT_DELAY_EXECUTION: Lazy<Option<extern "system" fn(BOOLEAN, *const i64) -> NTSTATUS>> =
@@ -94,17 +112,19 @@ pub fn sleep_until(target: Timestamp) {
 
     while now + MIN_SPIN_PERIOD < target {
         let sleep_duration = -((target - now - MIN_SPIN_PERIOD) as i64 + 99) / 100;
-        if let Some(delay_execution) = *NT_DELAY_EXECUTION {
-            unsafe {
-                delay_execution(false.into(), &sleep_duration).ok().unwrap();
-            }
+        let slept = if let Some(delay_execution) = *NT_DELAY_EXECUTION {
+            delay_execution(false.into(), &sleep_duration).ok().is_ok()
         } else {
-            TIMER.with(|timer| unsafe {
-                SetWaitableTimer(timer.0, &sleep_duration, 0, None, None, false)
-                    .ok()
-                    .unwrap();
-                WaitForSingleObject(timer.0, INFINITE).ok().unwrap();
-            });
+            TIMER.with(|timer| {
+                timer
+                    .as_ref()
+                    .is_some_and(|timer| timer.wait(sleep_duration))
+            })
+        };
+        if !slept {
+            // Without a high resolution timer, fall back to the coarse system sleep and let the
+            // spin below make up for its granularity.
+            thread::sleep(Duration::from_nanos(target - now - MIN_SPIN_PERIOD));
         }
         now = timestamp_now();
     }
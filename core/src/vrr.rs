@@ -0,0 +1,123 @@
+use std::cmp;
+
+use crate::config::ContextConfig;
+use crate::Interval;
+
+/// Relative distance the frame time has to fall below a low framerate compensation boundary
+/// before fewer repeats are used again. Keeps the repeat count from flapping around a boundary.
+const LFC_HYSTERESIS: f64 = 0.1;
+
+/// Shortest maximum refresh period accepted, which bounds the repeat count of slow frames.
+pub const MIN_VRR_PERIOD: Interval = 1_000_000;
+
+/// Refresh range of a variable refresh rate display.
+///
+/// Within the range, frames are kept at least `vrr_margin` slower than the maximum refresh rate,
+/// so that presentation never waits for the display. Below the range, the display repeats each
+/// frame (low framerate compensation). Frames are then kept at least `vrr_margin` longer than
+/// the boundary below their repeat count, so that the display doesn't switch between repeat
+/// counts from frame to frame.
+pub struct VrrRange {
+    min_period: Interval,
+    max_period: Interval,
+    /// Number of refreshes each frame is expected to be scanned out for.
+    repeats: u32,
+}
+
+impl VrrRange {
+    pub fn new(min_period: Interval, max_period: Interval) -> VrrRange {
+        VrrRange {
+            min_period,
+            max_period,
+            repeats: 1,
+        }
+    }
+
+    /// Minimum frame interval for a pipeline with the given frame time.
+    pub fn min_interval(
+        &mut self,
+        config: &ContextConfig,
+        frame_time: Option<Interval>,
+    ) -> Interval {
+        if let Some(frame_time) = frame_time {
+            let frame_time = frame_time as f64;
+            let max_period = self.max_period as f64;
+            let repeats = cmp::max((frame_time / max_period).ceil() as u32, 1);
+            // Fewer repeats are only used once the frame time is clearly below the boundary.
+            let hold = self.repeats > 1
+                && frame_time >= (self.repeats - 1) as f64 * max_period * (1. - LFC_HYSTERESIS);
+            if repeats > self.repeats || !hold {
+                self.repeats = repeats;
+            }
+        }
+        let repeats = self.repeats as Interval;
+        let period = cmp::min(self.min_period + config.vrr_margin, self.max_period);
+        if repeats == 1 {
+            return period;
+        }
+        // Frames shorter than `repeats - 1` maximum periods would be scanned out fewer times,
+        // which switches the compensation back and forth.
+        let boundary = cmp::min(
+            (repeats - 1) * self.max_period + config.vrr_margin,
+            repeats * self.max_period,
+        );
+        cmp::max(boundary, repeats * period)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lfc_hysteresis() {
+        let config = ContextConfig::default();
+        // 48-144Hz
+        let max_period = 20_833_333;
+        let mut range = VrrRange::new(6_944_444, max_period);
+        let min_interval = 6_944_444 + config.vrr_margin;
+
+        assert_eq!(range.min_interval(&config, Some(10_000_000)), min_interval);
+        assert_eq!(
+            range.min_interval(&config, Some(21_000_000)),
+            max_period + config.vrr_margin
+        );
+        // Just below the boundary, the frame is still repeated.
+        assert_eq!(
+            range.min_interval(&config, Some(20_000_000)),
+            max_period + config.vrr_margin
+        );
+        assert_eq!(range.min_interval(&config, Some(18_000_000)), min_interval);
+        assert_eq!(
+            range.min_interval(&config, Some(70_000_000)),
+            3 * max_period + config.vrr_margin
+        );
+        // The repeat count is computed directly, however far below the range the frame time is.
+        assert_eq!(
+            range.min_interval(&config, Some(10_000_000_000)),
+            480 * max_period + config.vrr_margin
+        );
+        assert_eq!(range.min_interval(&config, Some(10_000_000)), min_interval);
+    }
+
+    #[test]
+    fn test_lfc_pacing() {
+        let config = ContextConfig::default();
+        let (min_period, max_period) = (6_944_444, 20_833_333);
+        let mut range = VrrRange::new(min_period, max_period);
+        for frame_time in (1_000_000..100_000_000).step_by(250_000) {
+            let interval = cmp::max(frame_time, range.min_interval(&config, Some(frame_time)));
+            // Every refresh of a frame paced at this interval is within the range.
+            let period = interval / range.repeats as Interval;
+            assert!(
+                (min_period..=max_period).contains(&period),
+                "{frame_time}: {interval} / {}",
+                range.repeats
+            );
+        }
+
+        // Just past the boundary, frames are held back so that they stay repeated.
+        let frame_time = max_period + 100_000;
+        assert!(range.min_interval(&config, Some(frame_time)) > frame_time);
+    }
+}
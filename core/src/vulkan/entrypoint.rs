@@ -1,17 +1,19 @@
+use crate::error::{report, Error, Result, ResultCode};
+use crate::handle::{self, HandleKind};
 use crate::time::timestamp_now;
 use crate::vulkan::{Device, VulkanContext, VulkanSubmitAux};
 use crate::{Frame, MarkType};
 use spark::{vk, Builder};
+use std::ptr;
 use std::sync::Arc;
 
-#[no_mangle]
-pub unsafe extern "C" fn lfx2VulkanContextCreate(
+unsafe fn create_context(
     gipa: vk::FnGetInstanceProcAddr,
     instance: vk::Instance,
     physical_device: vk::PhysicalDevice,
     device: vk::Device,
     queue_family_index: u32,
-) -> *mut VulkanContext {
+) -> Result<Arc<VulkanContext>> {
     let loader = spark::Loader {
         fp_create_instance: None,
         fp_get_instance_proc_addr: Some(gipa),
@@ -29,26 +31,92 @@ pub unsafe extern "C" fn lfx2VulkanContextCreate(
         .collect::<Vec<_>>();
     let stub_device_create_info =
         vk::DeviceCreateInfo::builder().pp_enabled_extension_names(&device_extension_names);
-    let instance = spark::Instance::load(&loader, instance, &stub_instance_create_info).unwrap();
+    let instance = spark::Instance::load(&loader, instance, &stub_instance_create_info)
+        .map_err(|e| Error::Unsupported(format!("failed to load instance functions: {e:?}")))?;
     let device = spark::Device::load(
         &instance,
         device,
         &stub_device_create_info,
         vk::Version::from_raw_parts(1, 3, 0),
     )
-    .unwrap();
-    let device = Device::new(instance, physical_device, device, queue_family_index);
-    let context = VulkanContext::new(device).unwrap();
-    Arc::into_raw(context) as _
+    .map_err(|e| {
+        Error::Unsupported(format!(
+            "failed to load device functions, Vulkan 1.3 and VK_EXT_calibrated_timestamps are \
+             required: {e:?}"
+        ))
+    })?;
+    VulkanContext::new(Device::new(
+        instance,
+        physical_device,
+        device,
+        queue_family_index,
+    )?)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lfx2VulkanContextCreate(
+    gipa: vk::FnGetInstanceProcAddr,
+    instance: vk::Instance,
+    physical_device: vk::PhysicalDevice,
+    device: vk::Device,
+    queue_family_index: u32,
+) -> *mut VulkanContext {
+    match create_context(gipa, instance, physical_device, device, queue_family_index) {
+        Ok(context) => {
+            let context = Arc::into_raw(context);
+            handle::register(context, HandleKind::VulkanContext);
+            context as _
+        }
+        Err(e) => {
+            eprintln!("LFX2 WARN: Failed to create Vulkan context: {e}");
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Returns `Unsupported` if Vulkan 1.3 or VK_EXT_calibrated_timestamps is unavailable.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2VulkanContextCreateChecked(
+    gipa: vk::FnGetInstanceProcAddr,
+    instance: vk::Instance,
+    physical_device: vk::PhysicalDevice,
+    device: vk::Device,
+    queue_family_index: u32,
+    out_context: *mut *mut VulkanContext,
+) -> ResultCode {
+    if out_context.is_null() {
+        return report(Err(Error::InvalidArgument("out_context")));
+    }
+    *out_context = ptr::null_mut();
+    report(
+        create_context(gipa, instance, physical_device, device, queue_family_index).map(
+            |context| {
+                *out_context = Arc::into_raw(context) as _;
+                handle::register(*out_context, HandleKind::VulkanContext);
+            },
+        ),
+    )
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2VulkanContextAddRef(context: *mut VulkanContext) {
+    if !handle::guard(
+        "lfx2VulkanContextAddRef",
+        handle::add_ref(context, HandleKind::VulkanContext),
+    ) {
+        return;
+    }
     Arc::increment_strong_count(context);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2VulkanContextRelease(context: *mut VulkanContext) {
+    if !handle::guard(
+        "lfx2VulkanContextRelease",
+        handle::release(context, HandleKind::VulkanContext),
+    ) {
+        return;
+    }
     Arc::decrement_strong_count(context);
 }
 
@@ -56,7 +124,31 @@ pub unsafe extern "C" fn lfx2VulkanContextRelease(context: *mut VulkanContext) {
 pub unsafe extern "C" fn lfx2VulkanContextBeforeSubmit(
     context: *mut VulkanContext,
 ) -> VulkanSubmitAux {
-    (*context).inner.lock().submit().unwrap()
+    if !handle::valid(
+        "lfx2VulkanContextBeforeSubmit",
+        context,
+        HandleKind::VulkanContext,
+    ) {
+        return VulkanSubmitAux::default();
+    }
+    (*context).inner.lock().submit_or_fail().unwrap_or_default()
+}
+
+/// Handles are null if the result is not `Success`, in which case they must not be submitted.
+#[no_mangle]
+pub unsafe extern "C" fn lfx2VulkanContextBeforeSubmitChecked(
+    context: *mut VulkanContext,
+    out_aux: *mut VulkanSubmitAux,
+) -> ResultCode {
+    if out_aux.is_null() {
+        return report(Err(Error::InvalidArgument("out_aux")));
+    }
+    out_aux.write(VulkanSubmitAux::default());
+    if let Err(e) = handle::check(context, HandleKind::VulkanContext) {
+        return report(Err(e));
+    }
+    let result = (*context).inner.lock().submit_or_fail();
+    report(result.map(|aux| out_aux.write(aux)))
 }
 
 #[no_mangle]
@@ -64,6 +156,9 @@ pub unsafe extern "C" fn lfx2VulkanContextBeginFrame(
     context: *mut VulkanContext,
     frame: *mut Frame,
 ) {
+    if !check_frame("lfx2VulkanContextBeginFrame", context, frame) {
+        return;
+    }
     let frame = Arc::from_raw(frame);
     frame.mark(800, MarkType::Begin, timestamp_now());
     (*context).inner.lock().begin(&frame);
@@ -72,8 +167,24 @@ pub unsafe extern "C" fn lfx2VulkanContextBeginFrame(
 
 #[no_mangle]
 pub unsafe extern "C" fn lfx2VulkanContextEndFrame(context: *mut VulkanContext, frame: *mut Frame) {
+    if !check_frame("lfx2VulkanContextEndFrame", context, frame) {
+        return;
+    }
     let frame = Arc::from_raw(frame);
     (*context).inner.lock().end(&frame);
     frame.mark(800, MarkType::End, timestamp_now());
     let _ = Arc::into_raw(frame);
 }
+
+unsafe fn check_frame(function: &str, context: *mut VulkanContext, frame: *mut Frame) -> bool {
+    handle::valid(function, context, HandleKind::VulkanContext)
+        && handle::valid(function, frame, HandleKind::Frame)
+        && handle::guard(
+            function,
+            handle::check_owner(
+                context,
+                HandleKind::VulkanContext,
+                Arc::as_ptr(&(*frame).context),
+            ),
+        )
+}
@@ -1,17 +1,18 @@
 use std::mem;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 
 use parking_lot::Mutex;
 use spark::vk::{CommandBufferUsageFlags, QueryResultFlags};
 use spark::{vk, Builder};
 
+use crate::error::{Error, Result};
 use crate::fence_worker::{FenceThread, FenceWorkerMessage};
 use crate::time::{timestamp_from_vulkan, timestamp_now, VULKAN_TIMESTAMP_DOMAIN};
 use crate::{Frame, Timestamp};
 
 mod entrypoint;
 
-type VkResult<T> = Result<T, vk::Result>;
+type VkResult<T> = std::result::Result<T, vk::Result>;
 
 struct Device {
     handle: spark::Device,
@@ -26,18 +27,19 @@ impl Device {
         phys_device: vk::PhysicalDevice,
         device: spark::Device,
         queue_family_index: u32,
-    ) -> Arc<Device> {
+    ) -> Result<Arc<Device>> {
         unsafe {
             let limits = instance.get_physical_device_properties(phys_device).limits;
-            let queue_family_properties = instance
+            let queue_family_properties = *instance
                 .get_physical_device_queue_family_properties_to_vec(phys_device)
-                [queue_family_index as usize];
-            Arc::new(Device {
+                .get(queue_family_index as usize)
+                .ok_or(Error::InvalidArgument("queue family index"))?;
+            Ok(Arc::new(Device {
                 handle: device,
                 limits,
                 queue_family_index,
                 queue_family_properties,
-            })
+            }))
         }
     }
 }
@@ -125,45 +127,52 @@ struct VulkanContextInner {
     query_pool: Vec<(Arc<QueryPool>, u32)>,
 
     fence_thread: Option<FenceThread<VulkanSubmission>>,
+    current_frame: Option<Weak<Frame>>,
+    /// Set after an error, after which no more timing work is submitted.
+    failed: bool,
 
     sem: vk::Semaphore,
     seq: u64,
 }
 
+/// Handles are null if pacing was disabled after an error.
 #[repr(C)]
+#[derive(Default)]
 pub struct VulkanSubmitAux {
-    submit_before: vk::CommandBuffer,
-    submit_after: vk::CommandBuffer,
-    signal_sem: vk::Semaphore,
+    submit_before: Option<vk::CommandBuffer>,
+    submit_after: Option<vk::CommandBuffer>,
+    signal_sem: Option<vk::Semaphore>,
     signal_sem_value: u64,
 }
 
 impl VulkanContext {
-    fn new(device: Arc<Device>) -> VkResult<Arc<VulkanContext>> {
+    fn new(device: Arc<Device>) -> Result<Arc<VulkanContext>> {
         let ret = Arc::new(VulkanContext {
             inner: Mutex::new(VulkanContextInner::new(device)?),
         });
         let weak = Arc::downgrade(&ret);
         ret.inner.lock().fence_thread =
             Some(FenceThread::new(move |submission: VulkanSubmission| {
-                let ctx = weak.upgrade().unwrap();
-                submission.complete(&ctx).unwrap()
+                // Pending submissions are skipped while the context is being destroyed.
+                let Some(ctx) = weak.upgrade() else {
+                    return Ok(None);
+                };
+                Ok(Some(submission.complete(&ctx)?))
             }));
         Ok(ret)
     }
 }
 
 impl VulkanContextInner {
-    fn new(device: Arc<Device>) -> VkResult<Self> {
+    fn new(device: Arc<Device>) -> Result<Self> {
         let command_pool = unsafe {
             device.handle.create_command_pool(
                 &vk::CommandPoolCreateInfo::builder()
                     .queue_family_index(device.queue_family_index)
                     .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER),
                 None,
-            )
-        }
-        .unwrap();
+            )?
+        };
 
         let sem = unsafe {
             device.handle.create_semaphore(
@@ -181,6 +190,8 @@ impl VulkanContextInner {
             command_buffer: Vec::new(),
             query_pool: Vec::new(),
             fence_thread: None,
+            current_frame: None,
+            failed: false,
             sem,
             seq: 1,
         })
@@ -211,8 +222,8 @@ impl VulkanContextInner {
             let count = 16;
             let pool = QueryPool::new(self.device.clone(), vk::QueryType::TIMESTAMP, count)?;
             self.query_pool
-                .extend((0..count).map(|i| (pool.clone(), i)));
-            self.query_pool.pop().unwrap()
+                .extend((1..count).map(|i| (pool.clone(), i)));
+            (pool, 0)
         };
         unsafe {
             self.device.handle.reset_query_pool(pool.handle, idx, 1);
@@ -228,64 +239,84 @@ impl VulkanContextInner {
         }
     }
 
-    fn begin(&mut self, frame: &Arc<Frame>) {
+    fn send(&mut self, msg: FenceWorkerMessage<VulkanSubmission>) -> Result<()> {
         self.fence_thread
             .as_mut()
-            .unwrap()
-            .send(FenceWorkerMessage::BeginFrame(Arc::downgrade(frame)));
+            .ok_or(Error::WorkerGone)?
+            .send(msg)
+    }
+
+    /// Disables pacing for the current frame's context. No more timing work is submitted.
+    fn fail(&mut self, frame: Option<&Arc<Frame>>, error: &Error) {
+        self.failed = true;
+        let current_frame = self.current_frame.as_ref().and_then(Weak::upgrade);
+        if let Some(frame) = frame.or(current_frame.as_ref()) {
+            frame.fail(error);
+        }
+    }
+
+    fn begin(&mut self, frame: &Arc<Frame>) {
+        self.current_frame = Some(Arc::downgrade(frame));
+        if let Err(e) = self.send(FenceWorkerMessage::BeginFrame(Arc::downgrade(frame))) {
+            self.fail(Some(frame), &e);
+        }
     }
 
     fn end(&mut self, frame: &Arc<Frame>) {
-        self.fence_thread
-            .as_mut()
-            .unwrap()
-            .send(FenceWorkerMessage::EndFrame(frame.clone()));
+        self.current_frame = None;
+        if let Err(e) = self.send(FenceWorkerMessage::EndFrame(frame.clone())) {
+            self.fail(Some(frame), &e);
+        }
+    }
+
+    /// Disables pacing if submitting fails. Afterwards, nothing is submitted anymore.
+    fn submit_or_fail(&mut self) -> Result<VulkanSubmitAux> {
+        if self.failed {
+            return Err(Error::Degraded);
+        }
+        self.submit().map_err(|e| {
+            self.fail(None, &e);
+            e
+        })
     }
 
-    fn submit(&mut self) -> VkResult<VulkanSubmitAux> {
-        let queries = (0..2)
-            .map(|_| self.get_query_pool())
-            .collect::<VkResult<Vec<_>>>()?;
-        let command_buffers = (0..2)
-            .map(|_| self.get_command_buffer())
-            .collect::<VkResult<Vec<_>>>()?;
-        for i in 0..2 {
+    fn submit(&mut self) -> Result<VulkanSubmitAux> {
+        let queries = [self.get_query_pool()?, self.get_query_pool()?];
+        let command_buffers = [self.get_command_buffer()?, self.get_command_buffer()?];
+        for (command_buffer, (pool, index)) in command_buffers.iter().zip(&queries) {
             unsafe {
                 self.device.handle.begin_command_buffer(
-                    command_buffers[i].handle,
+                    command_buffer.handle,
                     &vk::CommandBufferBeginInfo::builder()
                         .flags(CommandBufferUsageFlags::ONE_TIME_SUBMIT),
                 )?;
                 self.device.handle.cmd_write_timestamp2(
-                    command_buffers[i].handle,
+                    command_buffer.handle,
                     vk::PipelineStageFlags2::ALL_COMMANDS,
-                    queries[i].0.handle,
-                    queries[i].1,
+                    pool.handle,
+                    *index,
                 );
                 self.device
                     .handle
-                    .end_command_buffer(command_buffers[i].handle)?;
+                    .end_command_buffer(command_buffer.handle)?;
             }
         }
         let seq = self.seq;
         self.seq += 1;
 
         let ret = VulkanSubmitAux {
-            submit_before: command_buffers[0].handle,
-            submit_after: command_buffers[1].handle,
-            signal_sem: self.sem,
+            submit_before: Some(command_buffers[0].handle),
+            submit_after: Some(command_buffers[1].handle),
+            signal_sem: Some(self.sem),
             signal_sem_value: seq,
         };
 
-        self.fence_thread
-            .as_mut()
-            .unwrap()
-            .send(FenceWorkerMessage::Wait(VulkanSubmission {
-                submission_ts: timestamp_now(),
-                queries: queries.try_into().map_err(|_| ()).unwrap(),
-                command_buffers: command_buffers.try_into().map_err(|_| ()).unwrap(),
-                seq,
-            }));
+        self.send(FenceWorkerMessage::Wait(VulkanSubmission {
+            submission_ts: timestamp_now(),
+            queries,
+            command_buffers,
+            seq,
+        }))?;
 
         Ok(ret)
     }